@@ -0,0 +1,119 @@
+//! Typed syntax tree produced by the [parser](crate::parser) and walked by the
+//! [compiler](crate::compiler) to generate bytecode.
+
+use crate::lexer::Token;
+
+/// Where a node starts in the source.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl From<&Token> for Span {
+    fn from(token: &Token) -> Self {
+        Self {
+            line: token.line,
+            col: token.col,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    /// An expression followed by a `;`. The result is discarded.
+    Expr { expr: Expr, span: Span },
+    /// `IF <cond> THEN ... ELSEIF <cond> THEN ... ELSE ... END`. The first branch is the `IF`
+    /// itself, every following one is an `ELSEIF`.
+    If {
+        branches: Vec<Branch>,
+        else_body: Option<Vec<Stmt>>,
+        span: Span,
+    },
+    /// `WHILE <cond> DO ... END`
+    While {
+        cond: Expr,
+        body: Vec<Stmt>,
+        span: Span,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Branch {
+    pub cond: Expr,
+    pub body: Vec<Stmt>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Nil(Span),
+    Bool(bool, Span),
+    Number(f64, Span),
+    String(String, Span),
+    /// `ALLOC`
+    Alloc(Span),
+    /// Read a global variable.
+    Var {
+        name: String,
+        span: Span,
+    },
+    /// `name = value`
+    Assign {
+        name: String,
+        value: Box<Expr>,
+        span: Span,
+    },
+    /// `callee(args...)`
+    Call {
+        callee: Box<Expr>,
+        args: Vec<Expr>,
+        span: Span,
+    },
+    /// `object.name`
+    Field {
+        object: Box<Expr>,
+        name: String,
+        span: Span,
+    },
+    /// `object.name = value`
+    FieldAssign {
+        object: Box<Expr>,
+        name: String,
+        value: Box<Expr>,
+        span: Span,
+    },
+    /// `object.name(args...)`
+    Invoke {
+        object: Box<Expr>,
+        name: String,
+        args: Vec<Expr>,
+        span: Span,
+    },
+}
+
+impl Stmt {
+    pub fn span(&self) -> Span {
+        match self {
+            Stmt::Expr { span, .. } | Stmt::If { span, .. } | Stmt::While { span, .. } => *span,
+        }
+    }
+}
+
+impl Expr {
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Nil(span)
+            | Expr::Bool(_, span)
+            | Expr::Number(_, span)
+            | Expr::String(_, span)
+            | Expr::Alloc(span)
+            | Expr::Var { span, .. }
+            | Expr::Assign { span, .. }
+            | Expr::Call { span, .. }
+            | Expr::Field { span, .. }
+            | Expr::FieldAssign { span, .. }
+            | Expr::Invoke { span, .. } => *span,
+        }
+    }
+}
@@ -0,0 +1,399 @@
+//! Binary serialization of compiled [`Module`]s (`.nacb` files), so scripts don't have to be
+//! lexed and compiled again every time they're run.
+//!
+//! All numbers are little endian. Strings are a `u32` byte length followed by UTF-8.
+//!
+//! ```text
+//! magic       "NACB"
+//! version     u16
+//! flags       u16             bit 0: a line table follows the code
+//! constants   u32 count, then per constant a u8 tag (0 = number, 1 = string) and its value
+//! globals     u32 count, then the names
+//! fields      u32 count, then the names
+//! code        u32 count, then per instruction a u8 opcode and its operands
+//! lines       u32 per instruction (only if flag bit 0 is set)
+//! ```
+
+use std::io::{Read, Write};
+
+use crate::{
+    Error,
+    compiler::{Constant, Module},
+    verifier,
+    vm::Instruction,
+};
+
+pub const MAGIC: &[u8; 4] = b"NACB";
+pub const FORMAT_VERSION: u16 = 2;
+/// Oldest version [`load`] still understands. Version 1 didn't have superinstructions, but is
+/// otherwise the same.
+const MIN_FORMAT_VERSION: u16 = 1;
+
+const FLAG_LINES: u16 = 1 << 0;
+
+const CONST_NUMBER: u8 = 0;
+const CONST_STRING: u8 = 1;
+
+const OP_LOAD: u8 = 0;
+const OP_STORE: u8 = 1;
+const OP_INDEX_GET: u8 = 2;
+const OP_INDEX_SET: u8 = 3;
+const OP_LOAD_NIL: u8 = 4;
+const OP_LOAD_TRUE: u8 = 5;
+const OP_LOAD_FALSE: u8 = 6;
+const OP_LOAD_CONST: u8 = 7;
+const OP_ALLOC: u8 = 8;
+const OP_CALL: u8 = 9;
+const OP_INVOKE: u8 = 10;
+const OP_JMP: u8 = 11;
+const OP_JMP_IF_FALSE: u8 = 12;
+const OP_POP: u8 = 13;
+const OP_HALT: u8 = 14;
+const OP_LOAD_GLOBAL_FIELD: u8 = 15;
+const OP_INDEX_SET_POP: u8 = 16;
+const OP_CALL_POP: u8 = 17;
+
+/// Serialize a module. The line table is only written if `with_lines` is set; without it
+/// the file is smaller but runtime errors can't point at the source.
+pub fn save(module: &Module, out: &mut impl Write, with_lines: bool) -> Result<(), Error> {
+    out.write_all(MAGIC)?;
+    out.write_all(&FORMAT_VERSION.to_le_bytes())?;
+
+    let flags = if with_lines { FLAG_LINES } else { 0 };
+    out.write_all(&flags.to_le_bytes())?;
+
+    write_len(out, module.constants.len())?;
+    for constant in module.constants.iter() {
+        match constant {
+            Constant::Number(num) => {
+                out.write_all(&[CONST_NUMBER])?;
+                out.write_all(&num.to_bits().to_le_bytes())?;
+            }
+            Constant::String(string) => {
+                out.write_all(&[CONST_STRING])?;
+                write_str(out, string)?;
+            }
+        }
+    }
+
+    for names in [&module.globals, &module.fields] {
+        write_len(out, names.len())?;
+        for name in names.iter() {
+            write_str(out, name)?;
+        }
+    }
+
+    write_len(out, module.code.len())?;
+    for inst in module.code.iter() {
+        match *inst {
+            Instruction::Load { index } => write_op(out, OP_LOAD, &index.to_le_bytes())?,
+            Instruction::Store { index } => write_op(out, OP_STORE, &index.to_le_bytes())?,
+            Instruction::IndexGet { index } => write_op(out, OP_INDEX_GET, &index.to_le_bytes())?,
+            Instruction::IndexSet { index } => write_op(out, OP_INDEX_SET, &index.to_le_bytes())?,
+            Instruction::LoadNil => write_op(out, OP_LOAD_NIL, &[])?,
+            Instruction::LoadTrue => write_op(out, OP_LOAD_TRUE, &[])?,
+            Instruction::LoadFalse => write_op(out, OP_LOAD_FALSE, &[])?,
+            Instruction::LoadConst { index } => write_op(out, OP_LOAD_CONST, &index.to_le_bytes())?,
+            Instruction::Alloc => write_op(out, OP_ALLOC, &[])?,
+            Instruction::Call { args } => write_op(out, OP_CALL, &[args])?,
+            Instruction::Invoke { args, sym } => {
+                write_op(out, OP_INVOKE, &[args])?;
+                out.write_all(&sym.to_le_bytes())?;
+            }
+            Instruction::Jmp { addr } => write_op(out, OP_JMP, &addr.to_le_bytes())?,
+            Instruction::JmpIfFalse { addr } => {
+                write_op(out, OP_JMP_IF_FALSE, &addr.to_le_bytes())?
+            }
+            Instruction::Pop => write_op(out, OP_POP, &[])?,
+            Instruction::Halt => write_op(out, OP_HALT, &[])?,
+            Instruction::LoadGlobalField { global, field } => {
+                write_op(out, OP_LOAD_GLOBAL_FIELD, &global.to_le_bytes())?;
+                out.write_all(&field.to_le_bytes())?;
+            }
+            Instruction::IndexSetPop { index } => {
+                write_op(out, OP_INDEX_SET_POP, &index.to_le_bytes())?
+            }
+            Instruction::CallPop { args } => write_op(out, OP_CALL_POP, &[args])?,
+        }
+    }
+
+    if with_lines {
+        for line in module.lines.iter() {
+            write_len(out, *line)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Deserialize a module written by [`save`]. The module is [verified](verifier::verify)
+/// before it's returned, so a corrupt or hand-crafted file can't crash the VM. Modules
+/// saved without a line table get line `0` for every instruction.
+pub fn load(input: &mut impl Read) -> Result<Module, Error> {
+    let mut reader = Reader { input };
+
+    let mut magic = [0; 4];
+    reader.input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a NAC bytecode file"));
+    }
+
+    let version = reader.u16()?;
+    if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+        return Err(invalid(format!(
+            "unsupported format version {version} (expected {MIN_FORMAT_VERSION} to {FORMAT_VERSION})"
+        )));
+    }
+
+    let flags = reader.u16()?;
+
+    let mut constants = vec![];
+    for _ in 0..reader.u32()? {
+        let constant = match reader.u8()? {
+            CONST_NUMBER => Constant::Number(f64::from_bits(reader.u64()?)),
+            CONST_STRING => Constant::String(reader.string()?),
+            tag => return Err(invalid(format!("unknown constant tag {tag}"))),
+        };
+        constants.push(constant);
+    }
+
+    let mut globals = vec![];
+    for _ in 0..reader.u32()? {
+        globals.push(reader.string()?);
+    }
+
+    let mut fields = vec![];
+    for _ in 0..reader.u32()? {
+        fields.push(reader.string()?);
+    }
+
+    let mut code = vec![];
+    for _ in 0..reader.u32()? {
+        let inst = match reader.u8()? {
+            OP_LOAD => Instruction::Load {
+                index: reader.u32()?,
+            },
+            OP_STORE => Instruction::Store {
+                index: reader.u32()?,
+            },
+            OP_INDEX_GET => Instruction::IndexGet {
+                index: reader.u32()?,
+            },
+            OP_INDEX_SET => Instruction::IndexSet {
+                index: reader.u32()?,
+            },
+            OP_LOAD_NIL => Instruction::LoadNil,
+            OP_LOAD_TRUE => Instruction::LoadTrue,
+            OP_LOAD_FALSE => Instruction::LoadFalse,
+            OP_LOAD_CONST => Instruction::LoadConst {
+                index: reader.u32()?,
+            },
+            OP_ALLOC => Instruction::Alloc,
+            OP_CALL => Instruction::Call { args: reader.u8()? },
+            OP_INVOKE => Instruction::Invoke {
+                args: reader.u8()?,
+                sym: reader.u32()?,
+            },
+            OP_JMP => Instruction::Jmp {
+                addr: reader.u32()? as i32,
+            },
+            OP_JMP_IF_FALSE => Instruction::JmpIfFalse {
+                addr: reader.u32()? as i32,
+            },
+            OP_POP => Instruction::Pop,
+            OP_HALT => Instruction::Halt,
+            OP_LOAD_GLOBAL_FIELD => Instruction::LoadGlobalField {
+                global: reader.u32()?,
+                field: reader.u32()?,
+            },
+            OP_INDEX_SET_POP => Instruction::IndexSetPop {
+                index: reader.u32()?,
+            },
+            OP_CALL_POP => Instruction::CallPop { args: reader.u8()? },
+            op => return Err(invalid(format!("unknown opcode {op}"))),
+        };
+        code.push(inst);
+    }
+
+    let lines = if flags & FLAG_LINES != 0 {
+        let mut lines = vec![];
+        for _ in 0..code.len() {
+            lines.push(reader.u32()? as usize);
+        }
+        lines
+    } else {
+        vec![0; code.len()]
+    };
+
+    let module = Module {
+        code,
+        lines,
+        constants,
+        globals,
+        fields,
+    };
+
+    verifier::verify(&module)?;
+
+    Ok(module)
+}
+
+fn invalid(reason: impl ToString) -> Error {
+    Error::InvalidBytecode(reason.to_string())
+}
+
+fn write_len(out: &mut impl Write, len: usize) -> Result<(), Error> {
+    let len = u32::try_from(len).map_err(|_| invalid("module is too big to save"))?;
+    out.write_all(&len.to_le_bytes())?;
+    Ok(())
+}
+
+fn write_str(out: &mut impl Write, string: &str) -> Result<(), Error> {
+    write_len(out, string.len())?;
+    out.write_all(string.as_bytes())?;
+    Ok(())
+}
+
+fn write_op(out: &mut impl Write, op: u8, operands: &[u8]) -> Result<(), Error> {
+    out.write_all(&[op])?;
+    out.write_all(operands)?;
+    Ok(())
+}
+
+struct Reader<'r, R> {
+    input: &'r mut R,
+}
+
+impl<R: Read> Reader<'_, R> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut bytes = [0; N];
+        self.input.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.bytes()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let len = self.u32()? as usize;
+        let mut bytes = vec![];
+        // Don't trust the length enough to allocate it all up front.
+        self.input
+            .by_ref()
+            .take(len as u64)
+            .read_to_end(&mut bytes)?;
+        if bytes.len() != len {
+            return Err(invalid("unexpected end of file"));
+        }
+
+        String::from_utf8(bytes).map_err(|_| invalid("string is not valid UTF-8"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler, lexer::LexerConfig, optimizer, parser};
+
+    const SRC: &str = "♥ A bit of everything.
+player = ALLOC;
+player.name = \"ß-Hero\";
+player.x = 15;
+IF player.x THEN print(player.name); ELSE print(nil); END
+WHILE false DO player.x = 0; END
+";
+
+    fn module(optimized: bool) -> Module {
+        let program = parser::parse(SRC, &LexerConfig::default()).unwrap();
+        let mut module = compiler::compile(&program).unwrap();
+        if optimized {
+            optimizer::optimize(&mut module);
+        }
+        module
+    }
+
+    fn save_to_vec(module: &Module, with_lines: bool) -> Vec<u8> {
+        let mut bytes = vec![];
+        save(module, &mut bytes, with_lines).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        for optimized in [false, true] {
+            let module = module(optimized);
+
+            let loaded = load(&mut save_to_vec(&module, true).as_slice()).unwrap();
+            assert_eq!(format!("{loaded:?}"), format!("{module:?}"));
+
+            let loaded = load(&mut save_to_vec(&module, false).as_slice()).unwrap();
+            assert_eq!(format!("{:?}", loaded.code), format!("{:?}", module.code));
+            assert_eq!(loaded.constants, module.constants);
+            assert_eq!(loaded.globals, module.globals);
+            assert_eq!(loaded.fields, module.fields);
+            assert_eq!(loaded.lines, vec![0; module.code.len()]);
+        }
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let bytes = save_to_vec(&module(true), true);
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[..4].copy_from_slice(b"NACX");
+        assert!(matches!(
+            load(&mut bad_magic.as_slice()),
+            Err(Error::InvalidBytecode(_))
+        ));
+
+        let mut too_new = bytes.clone();
+        too_new[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            load(&mut too_new.as_slice()),
+            Err(Error::InvalidBytecode(_))
+        ));
+
+        let mut too_old = bytes;
+        too_old[4..6].copy_from_slice(&0u16.to_le_bytes());
+        assert!(matches!(
+            load(&mut too_old.as_slice()),
+            Err(Error::InvalidBytecode(_))
+        ));
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let bytes = save_to_vec(&module(true), true);
+        for len in 0..bytes.len() {
+            let result = load(&mut &bytes[..len]);
+            assert!(
+                matches!(result, Err(Error::Io(_) | Error::InvalidBytecode(_))),
+                "{len}: {result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn version_1_files_still_load() {
+        // Version 1 is version 2 without superinstructions.
+        let module = module(false);
+        let mut bytes = save_to_vec(&module, true);
+        bytes[4..6].copy_from_slice(&1u16.to_le_bytes());
+
+        let loaded = load(&mut bytes.as_slice()).unwrap();
+        assert_eq!(format!("{loaded:?}"), format!("{module:?}"));
+    }
+}
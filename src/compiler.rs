@@ -1,401 +1,312 @@
-use std::{iter::Peekable, slice::Iter};
-
 use crate::{
     Error,
-    lexer::{Token, TokenKind},
-    vm::{Instruction, Runtime},
+    ast::{Branch, Expr, Stmt},
+    vm::Instruction,
 };
 
-pub fn compile(tokens: Vec<Token>, runtime: &mut Runtime) -> Result<Module, Error> {
+pub fn compile(program: &[Stmt]) -> Result<Module, Error> {
     let mut compiler = Compiler {
-        runtime,
-        // globals: Default::default(),
-        // field_to_id_map: ahash::HashMap::default(),
-        tokens: tokens.iter().peekable(),
         code: vec![],
-        constants: vec![],
+        lines: vec![],
+        line: 1,
+        constants: Default::default(),
+        globals: Default::default(),
+        fields: Default::default(),
     };
 
-    while compiler.tokens.peek().is_some() {
-        compiler.compile_statement()?;
+    for stmt in program {
+        compiler.compile_statement(stmt)?;
     }
 
-    compiler.code.push(Instruction::Halt);
+    compiler.emit(Instruction::Halt);
 
     Ok(Module {
-        constants: compiler.constants,
+        constants: compiler.constants.constants,
         code: compiler.code,
+        lines: compiler.lines,
+        globals: compiler.globals.names,
+        fields: compiler.fields.names,
     })
 }
 
+/// Compiled bytecode. A module doesn't depend on the runtime it's going to run in: globals,
+/// fields and strings are referred to by name through the module's own tables. Use
+/// [`Runtime::load`](crate::vm::Runtime::load) to link it into a runtime before running it.
 #[derive(Debug)]
 pub struct Module {
     pub code: Vec<Instruction>,
-    pub constants: Vec<f64>,
+    /// The source line each instruction was generated from.
+    pub lines: Vec<usize>,
+    pub constants: Vec<Constant>,
+    /// Names of the globals used by `Load` and `Store`.
+    pub globals: Vec<String>,
+    /// Names of the fields used by `IndexGet`, `IndexSet` and `Invoke`.
+    pub fields: Vec<String>,
 }
 
-pub struct Compiler<'s> {
-    runtime: &'s mut Runtime,
-    // globals: HashMap<String, u32>,
-    // field_to_id_map: ahash::HashMap<String, u32>,
-    tokens: Peekable<Iter<'s, Token>>,
-    code: Vec<Instruction>,
-    constants: Vec<f64>,
+/// An entry in a module's constant pool. Loaded with [`Instruction::LoadConst`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    Number(f64),
+    String(String),
 }
 
-impl<'s> Compiler<'s> {
-    pub fn consume(&mut self, expected: TokenKind) -> Result<(), Error> {
-        if let Some(token) = self.tokens.next() {
-            if token.kind == expected {
-                Ok(())
-            } else {
-                Err(Error::UnexpectedTokenExpected(token.kind, expected))
-            }
-        } else {
-            Err(Error::UnexpectedEOFExpected(expected))
-        }
+/// Identity of a constant for deduplication. Numbers are compared bit for bit, so `0` and
+/// `-0` (or two different NaNs) get separate entries.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    String(String),
+}
+
+/// A module's constant pool under construction.
+#[derive(Default)]
+pub struct ConstantPool {
+    pub constants: Vec<Constant>,
+    ids: ahash::HashMap<ConstantKey, u32>,
+}
+
+impl ConstantPool {
+    /// Add a constant to the pool, reusing the existing entry if there is one.
+    pub fn add(&mut self, constant: Constant) -> u32 {
+        let key = match &constant {
+            Constant::Number(num) => ConstantKey::Number(num.to_bits()),
+            Constant::String(string) => ConstantKey::String(string.clone()),
+        };
+
+        *self.ids.entry(key).or_insert_with(|| {
+            let idx = self.constants.len();
+            debug_assert!(idx < u32::MAX as usize, "bug: too many constants");
+            self.constants.push(constant);
+            idx as u32
+        })
     }
+}
 
-    pub fn compile_statement(&mut self) -> Result<(), Error> {
-        while let Some(token) = self.tokens.peek() {
-            match token.kind {
-                TokenKind::Semicolon => {
-                    self.tokens.next();
-                    continue;
-                }
-
-                TokenKind::Nil
-                | TokenKind::True
-                | TokenKind::False
-                | TokenKind::Number
-                | TokenKind::Ident
-                | TokenKind::String => {
-                    self.compile_member()?;
-                    self.consume(TokenKind::Semicolon)?;
-                    self.code.push(Instruction::Pop);
-                }
-
-                TokenKind::End | TokenKind::Else | TokenKind::ElseIf => {
-                    break;
-                }
-
-                TokenKind::Minus => todo!(),
-                TokenKind::If => self.compile_if_stmt()?,
-                TokenKind::While => self.compile_while_stmt()?,
-
-                _ => {
-                    return Err(Error::UnexpectedToken((*token).clone()));
-                }
+/// A module's global or field names under construction.
+#[derive(Default)]
+pub struct NameTable {
+    pub names: Vec<String>,
+    ids: ahash::HashMap<String, u32>,
+}
+
+impl NameTable {
+    /// Find `name` in the table, adding it if it isn't there yet.
+    pub fn index(&mut self, name: &str) -> u32 {
+        match self.ids.get(name) {
+            Some(id) => *id,
+            None => {
+                let id = self.names.len() as u32;
+                self.names.push(name.to_string());
+                self.ids.insert(name.to_string(), id);
+                id
             }
         }
-
-        Ok(())
     }
+}
 
-    fn compile_while_stmt(&mut self) -> Result<(), Error> {
-        self.consume(TokenKind::While)?;
+pub struct Compiler {
+    code: Vec<Instruction>,
+    lines: Vec<usize>,
+    /// Line of the statement or expression currently being compiled.
+    line: usize,
+    constants: ConstantPool,
+    globals: NameTable,
+    fields: NameTable,
+}
 
-        let start = self.code.len();
-        self.compile_member()?;
+impl Compiler {
+    /// Emit an instruction, returning its address. Forward jumps are emitted with a
+    /// placeholder offset and fixed up with [`Compiler::patch_jump`] once the target is known.
+    fn emit(&mut self, inst: Instruction) -> usize {
+        let addr = self.code.len();
+        self.code.push(inst);
+        self.lines.push(self.line);
+        addr
+    }
 
-        let jump = self.code.len();
-        self.code.push(Instruction::JmpIfFalse { addr: 0xdead });
+    /// Point the jump at `addr` to the next instruction that will be emitted.
+    fn patch_jump(&mut self, addr: usize) {
+        // Jumps are relative to the instruction following the jump.
+        let offset = (self.code.len() - addr - 1) as i32;
+        self.code[addr] = match self.code[addr] {
+            Instruction::Jmp { .. } => Instruction::Jmp { addr: offset },
+            Instruction::JmpIfFalse { .. } => Instruction::JmpIfFalse { addr: offset },
+            inst => unreachable!("bug: {inst:?} is not a jump"),
+        };
+    }
 
-        self.consume(TokenKind::Do)?;
+    pub fn compile_statement(&mut self, stmt: &Stmt) -> Result<(), Error> {
+        self.line = stmt.span().line;
 
-        while let Some(token) = self.tokens.peek() {
-            if token.kind != TokenKind::End {
-                self.compile_statement()?;
-            } else {
-                break;
+        match stmt {
+            Stmt::Expr { expr, .. } => {
+                self.compile_expr(expr)?;
+                self.emit(Instruction::Pop);
             }
+            Stmt::If {
+                branches,
+                else_body,
+                ..
+            } => self.compile_if_stmt(branches, else_body.as_deref())?,
+            Stmt::While { cond, body, .. } => self.compile_while_stmt(cond, body)?,
         }
 
-        self.consume(TokenKind::End)?;
+        Ok(())
+    }
 
-        let end = self.code.len();
-        self.code.push(Instruction::Jmp {
-            addr: start as i32 - end as i32 - 1,
-        });
-        self.code[jump] = Instruction::JmpIfFalse {
-            addr: (end - jump) as i32,
-        };
+    fn compile_block(&mut self, body: &[Stmt]) -> Result<(), Error> {
+        for stmt in body {
+            self.compile_statement(stmt)?;
+        }
 
         Ok(())
     }
 
-    fn compile_if_stmt(&mut self) -> Result<(), Error> {
-        // Consume the IF token.
-        // println!("{:?}", self.tokens.next());
-        self.consume(TokenKind::If)?;
+    fn compile_while_stmt(&mut self, cond: &Expr, body: &[Stmt]) -> Result<(), Error> {
+        let start = self.code.len();
+        self.compile_expr(cond)?;
+
+        let exit_jump = self.emit(Instruction::JmpIfFalse { addr: 0 });
 
-        self.compile_member()?;
+        self.compile_block(body)?;
 
-        self.consume(TokenKind::Then)?;
+        let end = self.code.len();
+        self.emit(Instruction::Jmp {
+            addr: start as i32 - end as i32 - 1,
+        });
+        self.patch_jump(exit_jump);
 
-        // Position of last jump instruction emitted by compiler.
-        let mut last_jmp_inst = self.code.len();
-        self.code.push(Instruction::JmpIfFalse { addr: 0xdead });
-        let mut has_else = false;
-        // self.code.push(Instruction::Pop); // Pop the condition off the stack.
+        Ok(())
+    }
 
+    fn compile_if_stmt(
+        &mut self,
+        branches: &[Branch],
+        else_body: Option<&[Stmt]>,
+    ) -> Result<(), Error> {
         /*
         // if
         <Cond>
-        JmpIfFalse --+      set prev_branch
-        <Pop>        |
+        JmpIfFalse --+
         <Stmt>       |
         <...>        |
-        Jmp ---------|-+    store in `jumps`
+        Jmp ---------|-+    store in `exit_jumps`
+        // elseif    | |
         <Cond> <-----+ |
-        // elseif      |
-        JmpIfFalse --+ |    set prev_branch
-        <Pop>        | |
+        JmpIfFalse --+ |
         <Stmt>       | |
         <...>        | |
-        Jmp ---------|-|-+  store in `jumps`
+        Jmp ---------|-|-+  store in `exit_jumps`
         // else      | | |
         <Stmt> <-----+ | |
         <Stmt>         | |
         <END>  <-------+-+
          */
 
-        // Absolute jumps that need to be patched.
-        let mut jumps: Vec<usize> = vec![];
-
-        while let Some(token) = self.tokens.peek() {
-            // TODO: get ELSE and ELSEIF's working.
-            if token.kind == TokenKind::Else {
-                self.consume(TokenKind::Else)?;
-
-                let jump_inst = self.code.len();
-                jumps.push(jump_inst);
-                self.code.push(Instruction::Jmp { addr: 0xdead_b0b }); // In honor of Bob Nystrom.
-
-                // Update the last jump instruction so that it jumps to this branch.
-                self.code[last_jmp_inst] = Instruction::JmpIfFalse {
-                    addr: (jump_inst - last_jmp_inst) as i32,
-                };
-
-                has_else = true;
-            } else if token.kind == TokenKind::ElseIf {
-                // Consume the ELSEIF token.
-                self.consume(TokenKind::ElseIf)?;
-
-                // Emit an unconditional jump instruction for the previous branch to take.
-                let jump_inst = self.code.len();
-                // This instruction will need to be updated after we compile all clauses so
-                // we store it for later.
-                jumps.push(jump_inst);
-                // Emit a placeholder instruction that will be updated later.
-                self.code.push(Instruction::Jmp { addr: 0xdead_b0b }); // In honor of Bob Nystrom.
-
-                // Update the last jump instruction so that it jumps to this branch.
-                self.code[last_jmp_inst] = Instruction::JmpIfFalse {
-                    addr: (jump_inst - last_jmp_inst) as i32,
-                };
-
-                // Compile the branch condition.
-                self.compile_member()?;
-
-                last_jmp_inst = self.code.len();
-                // Emit the instruction to skip this block and go to the next.
-                self.code.push(Instruction::JmpIfFalse { addr: 0xdead_b0b });
-                // Emit instruction to pop condition value off of stack.
-                // self.code.push(Instruction::Pop);
-
-                self.consume(TokenKind::Then)?;
-            } else if token.kind == TokenKind::End {
-                self.consume(TokenKind::End)?;
-                break;
-            } else {
-                self.compile_statement()?;
-            }
-        }
+        // Jumps to the end of the statement that need to be patched.
+        let mut exit_jumps = vec![];
 
-        let last = self.code.len() as i32;
+        for (n, branch) in branches.iter().enumerate() {
+            self.line = branch.span.line;
+            self.compile_expr(&branch.cond)?;
+            let next_branch = self.emit(Instruction::JmpIfFalse { addr: 0 });
 
-        if !has_else {
-            // Update the last jump instruction so that it jumps to this branch.
-            self.code[last_jmp_inst] = Instruction::JmpIfFalse {
-                addr: last - last_jmp_inst as i32 - 1,
-            };
-        }
+            self.compile_block(&branch.body)?;
 
-        let last = self.code.len() as i32;
+            // The last branch falls through to the end on its own.
+            let is_last = n == branches.len() - 1 && else_body.is_none();
+            if !is_last {
+                exit_jumps.push(self.emit(Instruction::Jmp { addr: 0 }));
+            }
 
-        // println!("{jumps:?}");
+            self.patch_jump(next_branch);
+        }
 
-        for jump in jumps {
-            let addr = last - jump as i32 - 1;
-            self.code[jump] = Instruction::Jmp { addr };
+        if let Some(body) = else_body {
+            self.compile_block(body)?;
+        }
+
+        for jump in exit_jumps {
+            self.patch_jump(jump);
         }
 
         Ok(())
     }
 
-    fn compile_member(&mut self) -> Result<(), Error> {
-        self.compile_atom()?;
-
-        while let Some(token) = self.tokens.peek() {
-            if token.kind == TokenKind::Dot {
-                self.tokens.next();
-
-                let Some(next_token) = self.tokens.next() else {
-                    return Err(Error::UnexpectedEOF);
-                };
-
-                if next_token.kind == TokenKind::Ident {
-                    let name = next_token.data.clone();
-
-                    // If the next token is an equal sign, then this becomes a store
-                    // operation. If the next token is a left parentheses, then this becomes
-                    // a method invocation operation.
-                    if let Some(token) = self.tokens.peek() {
-                        match token.kind {
-                            TokenKind::Equal => {
-                                // Emit an `IndexSet` instruction.
-                                // let name = token.data.clone();
-                                self.consume(TokenKind::Equal)?;
-
-                                self.compile_member()?;
-
-                                let id = self.runtime.get_field_index(&name);
-                                self.code.push(Instruction::IndexSet { index: id });
-                            }
-                            TokenKind::LParen => {
-                                let sym = self.runtime.get_field_index(&name);
-
-                                // Emit an `Invoke` instruction.
-                                self.consume(TokenKind::LParen)?;
-
-                                let mut args = 0u8;
-                                while let Some(token) = self.tokens.peek() {
-                                    if token.kind == TokenKind::RParen {
-                                        break;
-                                    } else {
-                                        args += 1;
-                                        self.compile_member()?;
-
-                                        // Optional trailing comma.
-                                        if let Some(token) = self.tokens.peek() {
-                                            if token.kind == TokenKind::Comma {
-                                                self.consume(TokenKind::Comma)?;
-                                            } else {
-                                                break;
-                                            }
-                                        }
-                                    }
-                                }
-
-                                self.consume(TokenKind::RParen)?;
-
-                                self.code.push(Instruction::Invoke { args, sym });
-                            }
-                            _ => {
-                                let id = self.runtime.get_field_index(&name);
-                                self.code.push(Instruction::IndexGet { index: id });
-
-                                continue;
-                            }
-                        }
-                    }
-                } else {
-                    return Err(Error::UnexpectedToken(next_token.clone()));
-                }
-            } else {
-                break;
-            }
+    fn compile_args(&mut self, args: &[Expr]) -> Result<u8, Error> {
+        for arg in args {
+            self.compile_expr(arg)?;
         }
 
-        Ok(())
+        debug_assert!(args.len() <= u8::MAX as usize, "bug: too many arguments");
+        Ok(args.len() as u8)
     }
 
-    fn compile_atom(&mut self) -> Result<(), Error> {
-        // Consume the current token and compile it.
-        if let Some(token) = self.tokens.next() {
-            match token.kind {
-                TokenKind::Ident => {
-                    // If this identifier is immediately followed by an equal sign, then we
-                    // this becomes a store operation instead of a load operation.
-                    if let Some(next_token) = self.tokens.peek() {
-                        if next_token.kind == TokenKind::Equal {
-                            let name = token.data.clone();
-                            self.consume(TokenKind::Equal)?;
-
-                            // Compile the left hand side of the assignment.
-                            self.compile_member()?;
-
-                            let id = self.runtime.get_global_index(&name) as u32;
-                            self.code.push(Instruction::Store { index: id });
-
-                            return Ok(());
-                        }
-                    }
-
-                    let id = self.runtime.get_global_index(&token.data) as u32;
-                    self.code.push(Instruction::Load { index: id });
-
-                    if let Some(token) = self.tokens.peek() {
-                        if token.kind == TokenKind::LParen {
-                            //let sym = self.field_to_id_map.len() as u32;
-                            //   let sym_name = token.data.clone();
-                            //self.field_to_id_map.insert(sym_name, sym);
-
-                            // Emit an `Invoke` instruction.
-                            self.consume(TokenKind::LParen)?;
-
-                            let mut args = 0u8;
-                            while let Some(token) = self.tokens.peek() {
-                                if token.kind == TokenKind::RParen {
-                                    break;
-                                } else {
-                                    args += 1;
-                                    self.compile_member()?;
-
-                                    // Optional trailing comma.
-                                    if let Some(token) = self.tokens.peek() {
-                                        if token.kind == TokenKind::Comma {
-                                            self.consume(TokenKind::Comma)?;
-                                        } else {
-                                            break;
-                                        }
-                                    }
-                                }
-                            }
-
-                            self.consume(TokenKind::RParen)?;
-
-                            self.code.push(Instruction::Call { args });
-                        }
-                    }
-                }
-                TokenKind::String => {
-                    let len = token.data.len();
-                    let value = &token.data.clone()[1..len - 1];
-                    let index = self.runtime.interner.intern(value.to_string());
-                    self.code.push(Instruction::LoadString { index });
-                }
-                TokenKind::Number => {
-                    let num = token.data.parse::<f64>().expect("bug: bad float");
-                    let idx = self.constants.len();
-                    debug_assert!(idx < u32::MAX as usize, "bug: too many constants");
-                    self.constants.push(num);
-                    self.code.push(Instruction::LoadConst { index: idx as u32 });
-                }
-                TokenKind::True => {
-                    self.code.push(Instruction::LoadTrue);
-                }
-                TokenKind::False => {
-                    self.code.push(Instruction::LoadFalse);
-                }
-                TokenKind::Nil => {
-                    self.code.push(Instruction::LoadNil);
-                }
-                TokenKind::Alloc => {
-                    self.code.push(Instruction::Alloc);
-                }
-                _ => return Err(Error::UnexpectedToken(token.clone())),
+    fn compile_expr(&mut self, expr: &Expr) -> Result<(), Error> {
+        self.line = expr.span().line;
+
+        match expr {
+            Expr::Nil(_) => {
+                self.emit(Instruction::LoadNil);
+            }
+            Expr::Bool(true, _) => {
+                self.emit(Instruction::LoadTrue);
+            }
+            Expr::Bool(false, _) => {
+                self.emit(Instruction::LoadFalse);
+            }
+            Expr::Number(num, _) => {
+                let index = self.constants.add(Constant::Number(*num));
+                self.emit(Instruction::LoadConst { index });
+            }
+            Expr::String(value, _) => {
+                let index = self.constants.add(Constant::String(value.clone()));
+                self.emit(Instruction::LoadConst { index });
+            }
+            Expr::Alloc(_) => {
+                self.emit(Instruction::Alloc);
+            }
+            Expr::Var { name, .. } => {
+                let id = self.globals.index(name);
+                self.emit(Instruction::Load { index: id });
+            }
+            Expr::Assign { name, value, .. } => {
+                self.compile_expr(value)?;
+
+                // Assignments are expressions, so load the value back after storing it.
+                let id = self.globals.index(name);
+                self.emit(Instruction::Store { index: id });
+                self.emit(Instruction::Load { index: id });
+            }
+            Expr::Call { callee, args, .. } => {
+                self.compile_expr(callee)?;
+                let args = self.compile_args(args)?;
+                self.emit(Instruction::Call { args });
+            }
+            Expr::Field { object, name, .. } => {
+                self.compile_expr(object)?;
+                let id = self.fields.index(name);
+                self.emit(Instruction::IndexGet { index: id });
+            }
+            Expr::FieldAssign {
+                object,
+                name,
+                value,
+                ..
+            } => {
+                self.compile_expr(object)?;
+                self.compile_expr(value)?;
+                let id = self.fields.index(name);
+                self.emit(Instruction::IndexSet { index: id });
+            }
+            Expr::Invoke {
+                object, name, args, ..
+            } => {
+                self.compile_expr(object)?;
+                let sym = self.fields.index(name);
+                let args = self.compile_args(args)?;
+                self.emit(Instruction::Invoke { args, sym });
             }
         }
 
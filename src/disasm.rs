@@ -0,0 +1,319 @@
+//! Human readable listings of compiled [`Module`]s.
+//!
+//! Operands are resolved through the module's tables, so instead of `Load { index: 7 }` and
+//! `Jmp { addr: -12 }` you get the global's name and a label for the jump target:
+//!
+//! ```text
+//!          0     1  LoadConst       #0           ; 0
+//!          1        Store           i
+//!          2        Load            i
+//!          3        Pop
+//!         L0:
+//!  ┌───>   4     2  Load            lt
+//!  │       5        Load            i
+//!  │       6        LoadConst       #1           ; 10
+//!  │       7        Call            2
+//!  │ ┌─<   8        JmpIfFalse      L1           ; -> 17
+//!  │ │     9     3  Load            add
+//!  │ │    10        Load            i
+//!  │ │    11        LoadConst       #2           ; 1
+//!  │ │    12        Call            2
+//!  │ │    13        Store           i
+//!  │ │    14        Load            i
+//!  │ │    15        Pop
+//!  └─│─<  16        Jmp             L0           ; -> 4
+//!    │    L1:
+//!    └─>  17        Halt
+//! ```
+//!
+//! The columns are the address, the source line (blank when it's the same as the line above,
+//! or when the module has no line table), the instruction and its operands.
+
+use std::fmt::Write;
+
+use crate::{
+    compiler::{Constant, Module},
+    vm::Instruction,
+};
+
+/// Disassemble a whole module. If the `source` the module was compiled from is given, each
+/// run of instructions is preceded by the line of source it came from.
+pub fn disassemble(module: &Module, source: Option<&str>) -> String {
+    let jumps: Vec<Jump> = module
+        .code
+        .iter()
+        .enumerate()
+        .filter_map(|(addr, inst)| {
+            jump_offset(*inst).map(|offset| Jump {
+                from: addr,
+                to: (addr as i64 + 1 + offset as i64) as usize,
+                lane: 0,
+            })
+        })
+        .collect();
+
+    let jumps = assign_lanes(jumps);
+    let lanes = jumps.iter().map(|jump| jump.lane + 1).max().unwrap_or(0);
+
+    // Number the jump targets in address order.
+    let mut targets: Vec<usize> = jumps.iter().map(|jump| jump.to).collect();
+    targets.sort_unstable();
+    targets.dedup();
+    let label = |addr: usize| targets.binary_search(&addr).ok().map(|n| format!("L{n}"));
+
+    let source_lines: Vec<&str> = source.map(|src| src.lines().collect()).unwrap_or_default();
+
+    let mut out = String::new();
+    let mut prev_line = 0;
+
+    for (addr, inst) in module.code.iter().enumerate() {
+        let line = module.lines.get(addr).copied().unwrap_or(0);
+        let new_line = line != 0 && line != prev_line;
+
+        if let Some(text) = source_lines.get(line.wrapping_sub(1)).filter(|_| new_line) {
+            let _ = writeln!(
+                out,
+                "{} ; {line}: {}",
+                gutter(&jumps, lanes, addr, false),
+                text.trim()
+            );
+        }
+
+        if let Some(label) = label(addr) {
+            let _ = writeln!(out, "{}  {label}:", gutter(&jumps, lanes, addr, false));
+        }
+
+        let line_col = if new_line {
+            line.to_string()
+        } else {
+            String::new()
+        };
+        let (mnemonic, operands, comment) = describe(module, addr, *inst, &label);
+
+        let mut row = format!(
+            "{}{addr:>4}  {line_col:>4}  {mnemonic:<15} {operands:<12}",
+            gutter(&jumps, lanes, addr, true)
+        );
+        match comment {
+            Some(comment) => {
+                let _ = write!(row, " ; {comment}");
+            }
+            None => row.truncate(row.trim_end().len()),
+        }
+        let _ = writeln!(out, "{row}");
+
+        prev_line = line;
+    }
+
+    out
+}
+
+/// Name, operands and an optional comment for a single instruction.
+fn describe(
+    module: &Module,
+    addr: usize,
+    inst: Instruction,
+    label: &impl Fn(usize) -> Option<String>,
+) -> (&'static str, String, Option<String>) {
+    let global = |index: u32| name_or_index(&module.globals, index);
+    let field = |index: u32| format!(".{}", name_or_index(&module.fields, index));
+
+    match inst {
+        Instruction::Load { index } => ("Load", global(index), None),
+        Instruction::Store { index } => ("Store", global(index), None),
+        Instruction::IndexGet { index } => ("IndexGet", field(index), None),
+        Instruction::IndexSet { index } => ("IndexSet", field(index), None),
+        Instruction::LoadNil => ("LoadNil", String::new(), None),
+        Instruction::LoadTrue => ("LoadTrue", String::new(), None),
+        Instruction::LoadFalse => ("LoadFalse", String::new(), None),
+        Instruction::LoadConst { index } => {
+            let value = match module.constants.get(index as usize) {
+                Some(Constant::Number(num)) => num.to_string(),
+                Some(Constant::String(string)) => format!("{string:?}"),
+                None => "<invalid>".into(),
+            };
+            ("LoadConst", format!("#{index}"), Some(value))
+        }
+        Instruction::Alloc => ("Alloc", String::new(), None),
+        Instruction::Call { args } => ("Call", args.to_string(), None),
+        Instruction::Invoke { args, sym } => ("Invoke", format!("{}, {args}", field(sym)), None),
+        Instruction::Jmp { addr: offset } | Instruction::JmpIfFalse { addr: offset } => {
+            let mnemonic = match inst {
+                Instruction::Jmp { .. } => "Jmp",
+                _ => "JmpIfFalse",
+            };
+            let target = addr as i64 + 1 + offset as i64;
+            let operand = usize::try_from(target)
+                .ok()
+                .and_then(label)
+                .unwrap_or_else(|| format!("{offset:+}"));
+            (mnemonic, operand, Some(format!("-> {target}")))
+        }
+        Instruction::Pop => ("Pop", String::new(), None),
+        Instruction::Halt => ("Halt", String::new(), None),
+        Instruction::LoadGlobalField {
+            global: index,
+            field: sym,
+        } => (
+            "LoadGlobalField",
+            format!("{}{}", global(index), field(sym)),
+            None,
+        ),
+        Instruction::IndexSetPop { index } => ("IndexSetPop", field(index), None),
+        Instruction::CallPop { args } => ("CallPop", args.to_string(), None),
+    }
+}
+
+fn name_or_index(names: &[String], index: u32) -> String {
+    match names.get(index as usize) {
+        Some(name) => name.clone(),
+        None => format!("<invalid {index}>"),
+    }
+}
+
+fn jump_offset(inst: Instruction) -> Option<i32> {
+    match inst {
+        Instruction::Jmp { addr } | Instruction::JmpIfFalse { addr } => Some(addr),
+        _ => None,
+    }
+}
+
+struct Jump {
+    from: usize,
+    to: usize,
+    /// Column of the arrow. Lane `0` is the one closest to the code.
+    lane: usize,
+}
+
+impl Jump {
+    fn top(&self) -> usize {
+        self.from.min(self.to)
+    }
+
+    fn bottom(&self) -> usize {
+        self.from.max(self.to)
+    }
+}
+
+/// Give every jump a lane so that arrows sharing a lane never overlap. Short jumps are placed
+/// first so they end up nested inside the longer ones.
+fn assign_lanes(mut jumps: Vec<Jump>) -> Vec<Jump> {
+    jumps.sort_by_key(|jump| (jump.bottom() - jump.top(), jump.top()));
+
+    for n in 0..jumps.len() {
+        let (placed, rest) = jumps.split_at_mut(n);
+        let jump = &mut rest[0];
+
+        jump.lane = (0..)
+            .find(|lane| {
+                placed.iter().all(|other| {
+                    other.lane != *lane
+                        || other.bottom() < jump.top()
+                        || other.top() > jump.bottom()
+                })
+            })
+            .expect("bug: ran out of lanes");
+    }
+
+    jumps
+}
+
+/// The arrow columns for one row. `is_inst` is false for label and source rows, which sit
+/// just above the instruction at `addr` and only continue the arrows passing through.
+fn gutter(jumps: &[Jump], lanes: usize, addr: usize, is_inst: bool) -> String {
+    if lanes == 0 {
+        return String::new();
+    }
+
+    // The outermost arrow that starts or ends on this row draws a horizontal line towards
+    // the code.
+    let horizontal = jumps
+        .iter()
+        .filter(|jump| is_inst && (jump.from == addr || jump.to == addr))
+        .map(|jump| jump.lane)
+        .max();
+
+    let mut out = String::from(" ");
+    for lane in (0..lanes).rev() {
+        let jump = jumps
+            .iter()
+            .find(|jump| jump.lane == lane && (jump.top()..=jump.bottom()).contains(&addr));
+        let is_horizontal = horizontal.is_some_and(|h| lane <= h);
+
+        let char = match jump {
+            Some(jump) if is_inst && addr == jump.top() => '┌',
+            Some(jump) if is_inst && addr == jump.bottom() => '└',
+            // The label row above the arrow's top isn't part of it yet.
+            Some(jump) if !is_inst && addr == jump.top() => ' ',
+            Some(_) => '│',
+            None if is_horizontal => '─',
+            None => ' ',
+        };
+        out.push(char);
+        out.push(if is_horizontal { '─' } else { ' ' });
+    }
+
+    let marker = if is_inst && jumps.iter().any(|jump| jump.to == addr) {
+        '>'
+    } else if horizontal.is_some() {
+        '<'
+    } else {
+        ' '
+    };
+    out.push(marker);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Instruction::*;
+
+    #[test]
+    fn overlapping_and_nested_jumps() {
+        let code = vec![
+            LoadTrue,
+            JmpIfFalse { addr: 4 },
+            LoadTrue,
+            JmpIfFalse { addr: 4 },
+            Jmp { addr: 0 },
+            Jmp { addr: -4 },
+            LoadNil,
+            Pop,
+            Halt,
+        ];
+        let module = Module {
+            lines: vec![1, 1, 2, 2, 3, 3, 4, 4, 5],
+            code,
+            constants: vec![],
+            globals: vec![],
+            fields: vec![],
+        };
+
+        // The loop back from 5 to 2 and the jump from 4 to 5 are nested inside the jump from 1
+        // to 6, which overlaps the jump from 3 to 8, so that one needs a lane of its own.
+        let expected = [
+            "           ; 1: a",
+            "             0     1  LoadTrue",
+            "   ┌─────<   1        JmpIfFalse      L2           ; -> 6",
+            "   │       ; 2: b",
+            "   │        L0:",
+            "   │ ┌───>   2     2  LoadTrue",
+            " ┌─│─│───<   3        JmpIfFalse      L3           ; -> 8",
+            " │ │ │     ; 3: c",
+            " │ │ │ ┌─<   4     3  Jmp             L1           ; -> 5",
+            " │ │ │ │    L1:",
+            " │ │ └─└─>   5        Jmp             L0           ; -> 2",
+            " │ │       ; 4: d",
+            " │ │        L2:",
+            " │ └─────>   6     4  LoadNil",
+            " │           7        Pop",
+            " │         ; 5: e",
+            " │          L3:",
+            " └───────>   8     5  Halt",
+        ];
+        let src = "a\nb\nc\nd\ne";
+        let listing = disassemble(&module, Some(src));
+        assert_eq!(listing.lines().collect::<Vec<_>>(), expected);
+    }
+}
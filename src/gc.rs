@@ -2,14 +2,28 @@
 //! managing it's memory at runtime: **YOU** 🫵 (Yes, we consider you to be state of
 //! the art. You should feel special).
 //!
+//! For when you're busy (or there's no display), other [`GcPolicy`]s can take over
+//! instead, see [`GcMode`].
 
 use std::time::{Duration, Instant};
 use egui_sdl2::egui;
 use sdl2::event::{Event, WindowEvent};
-use crate::vm::{ExternObject, Heap, HeapValue, Object, Runtime};
-
+use self::graph::Graph;
+use crate::{
+    Error,
+    vm::{ExternObject, Handle, Heap, HeapValue, Interner, Object, Runtime, Value},
+};
+
+mod difficulty;
+mod graph;
+mod metrics;
+mod policy;
 mod ui;
 
+pub use difficulty::Difficulty;
+pub use metrics::{CycleScore, GcMetrics};
+pub use policy::{GcPolicy, KeepEverything, ScriptedGc, TracingGc, run_cycle};
+
 const FULL_TITLE: &'static str = r#" _   _            _
 | \ | |_   _  ___| | ___  __ _ _ __
 |  \| | | | |/ __| |/ _ \/ _` | '__|
@@ -25,7 +39,75 @@ const FULL_TITLE: &'static str = r#" _   _            _
      \____|_| |_|\__,_|_|_| |_|___/\__,_| \_/\_/
 "#;
 
-pub fn gc_app(runtime: &mut Runtime) {
+/// Which [`GcPolicy`] collects garbage, as picked with `--gc`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum GcMode {
+    /// The player does, in the [`GcApp`].
+    #[default]
+    Manual,
+    /// The player does, but everything the tracer found reachable starts out marked.
+    Assisted,
+    /// [`TracingGc`] does, without opening a window.
+    Automatic,
+    /// Nothing is ever collected ([`KeepEverything`]).
+    Keep,
+    /// Decisions are read from this file ([`ScriptedGc`]).
+    Scripted(String),
+}
+
+impl std::str::FromStr for GcMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "manual" => Ok(GcMode::Manual),
+            "assisted" => Ok(GcMode::Assisted),
+            "automatic" => Ok(GcMode::Automatic),
+            "keep" => Ok(GcMode::Keep),
+            _ => match s.strip_prefix("script:") {
+                Some(path) => Ok(GcMode::Scripted(path.to_string())),
+                None => Err(Error::InvalidArgument(format!(
+                    "unknown GC mode '{s}' (expected manual, assisted, automatic, keep or \
+                     script:<file>)"
+                ))),
+            },
+        }
+    }
+}
+
+impl GcMode {
+    pub fn policy(&self) -> Result<Box<dyn GcPolicy>, Error> {
+        Ok(match self {
+            GcMode::Manual => Box::new(ManualGc { assisted: false }),
+            GcMode::Assisted => Box::new(ManualGc { assisted: true }),
+            GcMode::Automatic => Box::new(TracingGc),
+            GcMode::Keep => Box::new(KeepEverything),
+            GcMode::Scripted(path) => Box::new(ScriptedGc::load(path)?),
+        })
+    }
+}
+
+/// The player collects the garbage in the [`GcApp`]. If they close the window without
+/// finishing the cycle, everything is kept.
+pub struct ManualGc {
+    /// Start with everything [`Runtime::trace`] finds reachable already marked.
+    pub assisted: bool,
+}
+
+impl GcPolicy for ManualGc {
+    fn collect(&mut self, runtime: &mut Runtime) -> Vec<bool> {
+        let marked = if self.assisted {
+            runtime.trace()
+        } else {
+            vec![false; runtime.heap.size()]
+        };
+        gc_app(runtime, marked)
+    }
+}
+
+/// Open the GC window and let the player pick the objects to keep, starting with the ones in
+/// `marked` selected. Returns their final choice.
+pub fn gc_app(runtime: &mut Runtime, marked: Vec<bool>) -> Vec<bool> {
     // Look for an instance of an SDL context in the runtime's globals.
     let sdl = runtime
         .global_values()
@@ -48,24 +130,38 @@ pub fn gc_app(runtime: &mut Runtime) {
         .build()
         .expect("failed to create window");
 
-    let (mut event_pump, addr) = runtime
-        .globals
-        .iter()
+    // Before the event pump is borrowed below, so the root pointing at it isn't shown as freed.
+    let roots = Root::collect(runtime);
+
+    // SDL only allows one event pump at a time, so borrow the script's if it has one. Only
+    // that object is taken out of the heap; every other external object stays put.
+    let handle = runtime
+        .global_values()
         .filter_map(|value| value.try_as_extern())
-        .filter_map(|addr| runtime.heap.try_take_extern(addr).zip(Some(addr)))
-        .find_map(|(obj, addr)| obj.into_obj::<sdl2::EventPump>().zip(Some(addr)))
-        .unwrap_or_else(|| {
+        .find(|handle| {
+            runtime
+                .heap
+                .get_extern(*handle)
+                .is_some_and(|obj| obj.is::<sdl2::EventPump>())
+        });
+
+    let mut event_pump = match handle {
+        Some(handle) => runtime
+            .heap
+            .take_extern(handle)
+            .expect("bug: the event pump was just found")
+            .into_obj::<sdl2::EventPump>()
+            .expect("bug: not an event pump"),
+        None => {
             dbg!("creating new event pump");
-            (
-                Box::new(
-                    sdl.event_pump()
-                        .expect("failed to create event pump for SDL"),
-                ),
-                u32::MAX,
+            Box::new(
+                sdl.event_pump()
+                    .expect("failed to create event pump for SDL"),
             )
-        });
+        }
+    };
 
-    let mut app = GcApp::new(window, runtime);
+    let mut app = GcApp::new(window, runtime, marked, roots);
 
     while app.running {
         for event in event_pump.poll_iter() {
@@ -78,34 +174,135 @@ pub fn gc_app(runtime: &mut Runtime) {
 
     app.shutdown();
 
-    if addr != u32::MAX {
-        runtime.heap.insert(addr, *event_pump);
+    let mut marked = if app.finished {
+        app.marked
+    } else {
+        vec![true; runtime.heap.size()]
+    };
+
+    if let Some(handle) = handle {
+        runtime.heap.insert(handle, *event_pump);
+        // The player never saw the event pump, so it can't have been marked.
+        marked[handle.addr as usize] = true;
     }
+
+    marked
 }
 
-pub struct GcMetrics {
-    pub total_cycles: usize,
-    pub total_garbage_collected: usize,
+/// Something the program can reach objects from without going through the heap: a global
+/// or a slot on the VM stack.
+pub struct Root {
+    pub name: String,
+    /// The object it points at, if it's an object.
+    pub handle: Option<Handle>,
+    /// Whether `handle` still points at a live object.
+    pub live: bool,
+    /// The value, for roots that aren't objects.
+    pub text: String,
 }
 
-impl Default for GcMetrics {
-    fn default() -> Self {
+impl Root {
+    fn new(runtime: &Runtime, name: String, value: Value) -> Self {
+        let handle = match value {
+            Value::Object(handle) | Value::ExternObject(handle) => Some(handle),
+            _ => None,
+        };
+
         Self {
-            total_cycles: 0,
-            total_garbage_collected: 0,
+            name,
+            handle,
+            live: handle.is_some_and(|handle| runtime.heap.is_live(handle)),
+            text: runtime.format_value(value),
         }
     }
+
+    /// Every global except the functions, which can't point at anything, in name order,
+    /// followed by the stack from the bottom up.
+    fn collect(runtime: &Runtime) -> Vec<Root> {
+        let mut globals: Vec<(&str, Value)> = runtime
+            .globals()
+            .filter(|(_, value)| !matches!(value, Value::FunctionPtr(_)))
+            .collect();
+        globals.sort_by_key(|(name, _)| *name);
+
+        let globals = globals
+            .into_iter()
+            .map(|(name, value)| Root::new(runtime, name.to_string(), value));
+        let stack = runtime
+            .stack_values()
+            .enumerate()
+            .map(|(n, value)| Root::new(runtime, format!("stack[{n}]"), value));
+
+        globals.chain(stack).collect()
+    }
+}
+
+fn field_name(names: &ahash::HashMap<u32, String>, field: u32) -> String {
+    match names.get(&field) {
+        Some(name) => name.clone(),
+        None => format!("#{field}"),
+    }
+}
+
+/// The addresses in `marked` whose mark satisfies `f`.
+fn addrs_where(marked: &[bool], f: impl Fn(bool) -> bool) -> Vec<usize> {
+    (0..marked.len()).filter(|&addr| f(marked[addr])).collect()
+}
+
+/// Flip the marks at `addrs`, recording them on `undo` so the action can be reverted. Does nothing
+/// (and records nothing) if `addrs` is empty.
+fn flip_marks(marked: &mut [bool], undo: &mut Vec<Vec<usize>>, addrs: Vec<usize>) {
+    if addrs.is_empty() {
+        return;
+    }
+    for &addr in &addrs {
+        marked[addr] = !marked[addr];
+    }
+    undo.push(addrs);
+}
+
+/// How the heap panel shows the heap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HeapView {
+    /// Every cell in address order.
+    List,
+    /// Objects and the references between them, see [`Graph`].
+    Graph,
 }
 
 /// `egui` application allowing the user/player to manage _Nuclear Alabaster
 /// Chainsaw_'s heap.
 pub struct GcApp<'r> {
     egui: egui_sdl2::EguiCanvas,
-    heap: &'r mut Heap,
+    heap: &'r Heap,
+    interner: &'r Interner,
+    /// Names of the fields, by id.
+    field_names: ahash::HashMap<u32, String>,
     running: bool,
+    /// The player clicked "Finish Cycle", or ran out of time.
+    finished: bool,
+    /// When the cycle finishes on its own, if the difficulty has a time limit.
+    deadline: Option<Instant>,
+    timed_out: bool,
+    /// The player clicked "Finish Cycle" and is looking over what will be freed.
+    reviewing: bool,
     sweeping: bool,
     active_object: usize,
     marked: Vec<bool>,
+    /// The addresses each marking action in this cycle flipped, most recent last.
+    undo: Vec<Vec<usize>>,
+    /// What [`Runtime::trace`] found reachable, for hints and scoring.
+    reachable: Vec<bool>,
+    show_hints: bool,
+    /// Show object fields as typed values instead of raw bits.
+    decode_values: bool,
+    /// How the player did, once they've finished the cycle.
+    score: Option<CycleScore>,
+    roots: Vec<Root>,
+    /// Objects that a root points at directly.
+    rooted: Vec<bool>,
+    view: HeapView,
+    graph: Graph,
     metrics: &'r mut GcMetrics,
     sweep_time: Instant,
 }
@@ -114,16 +311,52 @@ impl<'r> GcApp<'r> {
     /// Create a new garbage collection application. This will attempt to reuse an existing SDL
     /// context if the runtime has already created one. If it can't find one, then it'll initialize
     /// a new one.  
-    pub fn new(window: sdl2::video::Window, runtime: &'r mut Runtime) -> Self {
+    ///
+    /// `roots` are collected by the caller, before it takes anything out of the heap.
+    pub fn new(
+        window: sdl2::video::Window,
+        runtime: &'r mut Runtime,
+        marked: Vec<bool>,
+        roots: Vec<Root>,
+    ) -> Self {
         let egui = egui_sdl2::EguiCanvas::new(window);
-        let size = runtime.heap.size();
+        assert!(marked.len() == runtime.heap.size());
+        let reachable = runtime.trace();
+
+        let mut rooted = vec![false; runtime.heap.size()];
+        for root in roots.iter().filter(|root| root.live) {
+            rooted[root.handle.expect("bug: live root without a handle").addr as usize] = true;
+        }
+
+        let field_names: ahash::HashMap<u32, String> = runtime
+            .field_ids()
+            .map(|(name, id)| (id, name.to_string()))
+            .collect();
+        let graph = Graph::new(&runtime.heap, &roots, |field| field_name(&field_names, field));
+
+        let difficulty = runtime.gc_metrics.difficulty;
 
         Self {
             egui,
-            heap: &mut runtime.heap,
+            heap: &runtime.heap,
+            interner: &runtime.interner,
+            field_names,
             running: true,
+            finished: false,
+            deadline: difficulty.time_limit().map(|limit| Instant::now() + limit),
+            timed_out: false,
+            reviewing: false,
             active_object: 0,
-            marked: vec![false; size],
+            marked,
+            undo: vec![],
+            reachable,
+            show_hints: difficulty.hints() && difficulty.helpful(),
+            decode_values: difficulty.decoding() && difficulty.helpful(),
+            score: None,
+            roots,
+            rooted,
+            view: HeapView::List,
+            graph,
             metrics: &mut runtime.gc_metrics,
             sweeping: false,
             sweep_time: Instant::now(),
@@ -150,67 +383,170 @@ impl<'r> GcApp<'r> {
         }
     }
 
+    /// Score the player's choices and start the sweeping animation.
+    fn finish_cycle(&mut self) {
+        self.score = Some(CycleScore::new(self.heap, &self.marked, &self.reachable));
+        self.finished = true;
+        self.reviewing = false;
+        self.sweeping = true;
+        self.sweep_time = Instant::now();
+    }
+
     pub fn update(&mut self) {
+        if !self.sweeping && self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            self.timed_out = true;
+            self.finish_cycle();
+        }
+
+        let difficulty = self.metrics.difficulty;
+        let mut finish = false;
+        let mut undo = false;
+
         self.egui.run(|ctx| {
+            if self.reviewing {
+                let freeing = self
+                    .heap
+                    .objects()
+                    .enumerate()
+                    .filter(|(addr, entry)| {
+                        !self.marked[*addr] && !matches!(entry, HeapValue::Free { .. })
+                    })
+                    .count();
+                let rooted: Vec<String> = self
+                    .roots
+                    .iter()
+                    .filter(|root| root.live)
+                    .filter_map(|root| {
+                        let value = self.heap.value_at(root.handle?.addr as usize)?;
+                        let freed: Vec<String> = self
+                            .heap
+                            .trace([value])
+                            .into_iter()
+                            .enumerate()
+                            .filter(|&(addr, reachable)| reachable && !self.marked[addr])
+                            .map(|(addr, _)| format!("0x{addr:0>6x}"))
+                            .collect();
+                        (!freed.is_empty())
+                            .then(|| format!("{} -> {}", root.name, freed.join(", ")))
+                    })
+                    .collect();
+
+                match ui::review_cycle(ctx, freeing, &rooted) {
+                    Some(true) => {
+                        finish = true;
+                    }
+                    Some(false) => self.reviewing = false,
+                    None => {}
+                }
+            }
+
             if self.sweeping {
                 let total_time = 1.6; // 2 seconds.
                 let time = self.sweep_time.elapsed().as_secs_f64();
                 ui::freeing_garbage(ctx, time, total_time, 0.6);
 
                 if time >= total_time {
-                    self.running = false;
+                    let score = self.score.as_ref().expect("bug: finished without a score");
+                    if ui::cycle_results(ctx, score, self.timed_out) {
+                        self.running = false;
+                    }
                 }
             }
 
             egui::CentralPanel::default().show(ctx, |ui| {
-                if self.sweeping {
+                if self.sweeping || self.reviewing {
                     ui.disable();
                 }
 
                 ui.horizontal(|ui| {
+                    egui::Frame::group(ui.style())
+                        .corner_radius(0)
+                        .show(ui, |ui| {
+                            ui::roots_panel(ui, &self.roots, &mut self.active_object);
+                        });
+
                     egui::Frame::group(ui.style())
                         .corner_radius(0)
                         .show(ui, |ui| {
                             ui.vertical(|ui: &mut egui::Ui| {
-                                for (addr, entry) in self.heap.objects().enumerate() {
-                                    ui.horizontal(|ui| {
-                                        if ui
-                                            .radio(addr == self.active_object, "")
-                                            .on_hover_text("View object")
-                                            .clicked()
-                                        {
-                                            self.active_object = addr;
-                                        }
+                                ui.horizontal(|ui| {
+                                    ui.selectable_value(&mut self.view, HeapView::List, "List");
+                                    ui.selectable_value(&mut self.view, HeapView::Graph, "Graph");
+                                });
+                                ui.separator();
 
-                                        ui.checkbox(&mut self.marked[addr], "")
-                                            .on_hover_text("Mark this object as not garbage");
+                                if self.view == HeapView::Graph {
+                                    egui::ScrollArea::both().show(ui, |ui| {
+                                        if let Some(addr) = self.graph.show(
+                                            ui,
+                                            &self.marked,
+                                            &mut self.active_object,
+                                            difficulty.decoding(),
+                                        ) {
+                                            flip_marks(&mut self.marked, &mut self.undo, vec![addr]);
+                                        }
+                                    });
+                                } else {
+                                    for (addr, entry) in self.heap.objects().enumerate() {
+                                        ui.horizontal(|ui| {
+                                            if ui
+                                                .radio(addr == self.active_object, "")
+                                                .on_hover_text("View object")
+                                                .clicked()
+                                            {
+                                                self.active_object = addr;
+                                            }
 
-                                        ui.label(
-                                            egui::RichText::new(format!("0x{:0>6x}", addr))
-                                                .color(egui::Color32::WHITE),
-                                        );
+                                            let mut marked = self.marked[addr];
+                                            if ui
+                                                .checkbox(&mut marked, "")
+                                                .on_hover_text("Mark this object as not garbage")
+                                                .changed()
+                                            {
+                                                flip_marks(&mut self.marked, &mut self.undo, vec![addr]);
+                                            }
 
-                                        let value = match entry {
-                                            HeapValue::Free { next } => *next,
-                                            HeapValue::Object(object) => {
-                                                (object as *const Object).addr()
+                                            if self.rooted[addr] {
+                                                ui.label(
+                                                    egui::RichText::new(format!("0x{:0>6x}", addr))
+                                                        .color(egui::Color32::LIGHT_BLUE),
+                                                )
+                                                .on_hover_text("Referenced directly by a root");
+                                            } else {
+                                                ui.label(
+                                                    egui::RichText::new(format!("0x{:0>6x}", addr))
+                                                        .color(egui::Color32::WHITE),
+                                                );
                                             }
-                                            HeapValue::Extern(object) => {
-                                                (object as *const ExternObject).addr()
+
+                                            if self.show_hints {
+                                                let in_use = !matches!(entry, HeapValue::Free { .. });
+                                                ui::reachability_hint(ui, in_use, self.reachable[addr]);
                                             }
-                                        };
 
-                                        let color = if self.marked[addr] {
-                                            egui::Color32::YELLOW
-                                        } else {
-                                            egui::Color32::LIGHT_GRAY
-                                        };
+                                            let value = match entry {
+                                                HeapValue::Free { next } => *next,
+                                                HeapValue::Object(object) => {
+                                                    (object as *const Object).addr()
+                                                }
+                                                HeapValue::Extern(object) => {
+                                                    (object as *const ExternObject).addr()
+                                                }
+                                            };
+
+                                            let color = if self.marked[addr] {
+                                                egui::Color32::YELLOW
+                                            } else {
+                                                egui::Color32::LIGHT_GRAY
+                                            };
+
+                                            ui.label(
+                                                egui::RichText::new(format!("0x{:0>6x}", value))
+                                                    .color(color),
+                                            );
+                                        });
+                                    }
 
-                                        ui.label(
-                                            egui::RichText::new(format!("0x{:0>6x}", value))
-                                                .color(color),
-                                        );
-                                    });
                                 }
 
                                 ui.horizontal(|ui| {
@@ -240,10 +576,24 @@ impl<'r> GcApp<'r> {
                                         );
                                     }
                                     HeapValue::Object(object) => {
+                                        let mut fields: Vec<u32> = object.data.keys().copied().collect();
+                                        fields.sort_unstable();
+
                                         ui.vertical(|ui| {
-                                            for (_field_id, value) in object.data.iter() {
+                                            for field in fields {
+                                                let value = object.get(field);
                                                 ui.horizontal(|ui| {
-                                                    ui::draw_object_field(ui, *value);
+                                                    if !self.decode_values {
+                                                        ui::draw_object_field(ui, value);
+                                                        return;
+                                                    }
+
+                                                    let name = field_name(&self.field_names, field);
+                                                    let clicked =
+                                                        ui::draw_decoded_field(ui, &name, value, self.heap, self.interner);
+                                                    if let Some(addr) = clicked {
+                                                        self.active_object = addr;
+                                                    }
                                                 });
                                             }
                                         });
@@ -278,12 +628,61 @@ impl<'r> GcApp<'r> {
                                     ui.label("♥ Worst case scenario, the program will SegFault.");
                                     ui.label("♥ Click the finish cycle button to resume the program.");
 
-                                    if ui.button("Finish Cycle").clicked() {
-                                        println!("Finishing the GC cycle");
-                                        self.sweeping = true;
-                                        self.metrics.total_garbage_collected += self.heap.sweep(&self.marked);
-                                        self.sweep_time = Instant::now();
+                                    if difficulty.hints() {
+                                        ui.checkbox(&mut self.show_hints, "Show hints")
+                                            .on_hover_text(
+                                                "Show which objects can be reached from the program's variables",
+                                            );
                                     }
+                                    if difficulty.decoding() {
+                                        ui.checkbox(&mut self.decode_values, "Decode values")
+                                            .on_hover_text("Show what's in each field instead of its bits");
+                                    }
+
+                                    if let Some(deadline) = self.deadline
+                                        && !self.sweeping
+                                    {
+                                        ui::countdown(ui, deadline.saturating_duration_since(Instant::now()));
+                                    }
+
+                                    ui.horizontal(|ui| {
+                                        if ui.button("Mark all").clicked() {
+                                            let unmarked = addrs_where(&self.marked, |marked| !marked);
+                                            flip_marks(&mut self.marked, &mut self.undo, unmarked);
+                                        }
+                                        if ui.button("Mark none").clicked() {
+                                            let marked = addrs_where(&self.marked, |marked| marked);
+                                            flip_marks(&mut self.marked, &mut self.undo, marked);
+                                        }
+                                        if ui.button("Invert").clicked() {
+                                            let all = (0..self.marked.len()).collect();
+                                            flip_marks(&mut self.marked, &mut self.undo, all);
+                                        }
+                                        if ui
+                                            .button("Mark from selected")
+                                            .on_hover_text("Mark the selected object and everything it refers to")
+                                            .clicked()
+                                            && let Some(value) = self.heap.value_at(self.active_object)
+                                        {
+                                            let reachable = self.heap.trace([value]);
+                                            let newly_marked = (0..self.marked.len())
+                                                .filter(|&addr| reachable[addr] && !self.marked[addr])
+                                                .collect();
+                                            flip_marks(&mut self.marked, &mut self.undo, newly_marked);
+                                        }
+                                    });
+
+                                    ui.horizontal(|ui| {
+                                        if ui
+                                            .add_enabled(!self.undo.is_empty(), egui::Button::new("Undo"))
+                                            .clicked()
+                                        {
+                                            undo = true;
+                                        }
+                                        if ui.button("Finish Cycle").clicked() {
+                                            self.reviewing = true;
+                                        }
+                                    });
                                 });
                             });
 
@@ -292,8 +691,12 @@ impl<'r> GcApp<'r> {
                             .show(ui, |ui| {
                                 ui.label(egui::RichText::new("GC Stats").heading());
                                 ui.separator();
+                                ui.label(format!("Difficulty: {}", difficulty.name()));
                                 ui.label(format!("Cycles survived: {}", self.metrics.total_cycles));
                                 ui.label(format!("Total garbage collected: {}", self.metrics.total_garbage_collected));
+                                ui.label(format!("Garbage freed: {}", self.metrics.garbage_freed));
+                                ui.label(format!("Live objects freed: {}", self.metrics.live_freed));
+                                ui.label(format!("Leaks kept: {}", self.metrics.leaks_kept));
                             });
 
                         egui::Frame::group(ui.style())
@@ -310,6 +713,16 @@ impl<'r> GcApp<'r> {
             });
         });
 
+        if undo && let Some(addrs) = self.undo.pop() {
+            for addr in addrs {
+                self.marked[addr] = !self.marked[addr];
+            }
+        }
+
+        if finish {
+            self.finish_cycle();
+        }
+
         self.egui.clear([255, 255, 255, 255]);
         self.egui.paint();
         self.egui.present();
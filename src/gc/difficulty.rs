@@ -0,0 +1,79 @@
+//! How hard the GC game is, as picked with `--difficulty`.
+
+use std::time::Duration;
+
+use crate::Error;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Difficulty {
+    /// A roomy heap, and hints and decoded values are on from the start.
+    Easy,
+    /// The game as it's always been: no time limit, and hints and decoded values can be
+    /// turned on.
+    #[default]
+    Normal,
+    /// A minute per cycle, and nothing but the raw bits.
+    Hard,
+    /// A cramped heap and half a minute per cycle, with nothing but the raw bits.
+    Nightmare,
+}
+
+impl Difficulty {
+    pub fn name(self) -> &'static str {
+        match self {
+            Difficulty::Easy => "easy",
+            Difficulty::Normal => "normal",
+            Difficulty::Hard => "hard",
+            Difficulty::Nightmare => "nightmare",
+        }
+    }
+
+    /// Number of objects the heap starts with, unless `--heap` says otherwise.
+    pub fn heap_size(self) -> usize {
+        match self {
+            Difficulty::Easy => 40,
+            Difficulty::Normal | Difficulty::Hard => 20,
+            Difficulty::Nightmare => 12,
+        }
+    }
+
+    /// How long the player has to finish a cycle before it's finished for them.
+    pub fn time_limit(self) -> Option<Duration> {
+        match self {
+            Difficulty::Easy | Difficulty::Normal => None,
+            Difficulty::Hard => Some(Duration::from_secs(60)),
+            Difficulty::Nightmare => Some(Duration::from_secs(30)),
+        }
+    }
+
+    /// Whether field names and decoded values can be shown.
+    pub fn decoding(self) -> bool {
+        matches!(self, Difficulty::Easy | Difficulty::Normal)
+    }
+
+    /// Whether the reachability hints can be shown.
+    pub fn hints(self) -> bool {
+        matches!(self, Difficulty::Easy | Difficulty::Normal)
+    }
+
+    /// Whether hints and decoded values start out on.
+    pub fn helpful(self) -> bool {
+        self == Difficulty::Easy
+    }
+}
+
+impl std::str::FromStr for Difficulty {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "easy" => Ok(Difficulty::Easy),
+            "normal" => Ok(Difficulty::Normal),
+            "hard" => Ok(Difficulty::Hard),
+            "nightmare" => Ok(Difficulty::Nightmare),
+            _ => Err(Error::InvalidArgument(format!(
+                "unknown difficulty '{s}' (expected easy, normal, hard or nightmare)"
+            ))),
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! The heap drawn as a graph: objects are boxes, fields that hold objects are labelled arrows
+//! between them, and the roots sit in a column on the left.
+//!
+//! Objects are laid out in columns by how many hops they are from a root, so everything a
+//! root points at is in the first column after the roots, what those point at in the next,
+//! and so on. Objects nothing can reach get a column of their own on the far right.
+
+use std::collections::VecDeque;
+
+use egui_sdl2::egui;
+
+use crate::{
+    gc::Root,
+    vm::{Heap, HeapValue, Value},
+};
+
+const COLUMN_WIDTH: f32 = 170.0;
+const ROW_HEIGHT: f32 = 48.0;
+const NODE_SIZE: egui::Vec2 = egui::vec2(100.0, 28.0);
+const MARGIN: f32 = 8.0;
+
+pub struct Graph {
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+    /// Names of the roots that point at an object, and the object.
+    roots: Vec<(String, usize)>,
+    columns: usize,
+    rows: usize,
+}
+
+struct Node {
+    addr: usize,
+    is_extern: bool,
+    column: usize,
+    row: usize,
+}
+
+struct Edge {
+    from: usize,
+    to: usize,
+    label: String,
+}
+
+impl Graph {
+    pub fn new(heap: &Heap, roots: &[Root], field_name: impl Fn(u32) -> String) -> Self {
+        let roots: Vec<(String, usize)> = roots
+            .iter()
+            .filter(|root| root.live)
+            .filter_map(|root| Some((root.name.clone(), root.handle?.addr as usize)))
+            .collect();
+
+        let live_target = |value: Value| match value {
+            Value::Object(handle) | Value::ExternObject(handle) if heap.is_live(handle) => {
+                Some(handle.addr as usize)
+            }
+            _ => None,
+        };
+
+        let mut edges = vec![];
+        for (addr, entry) in heap.objects().enumerate() {
+            if let HeapValue::Object(object) = entry {
+                let mut fields: Vec<_> = object.data.keys().copied().collect();
+                fields.sort_unstable();
+
+                for field in fields {
+                    if let Some(to) = live_target(object.get(field)) {
+                        edges.push(Edge {
+                            from: addr,
+                            to,
+                            label: field_name(field),
+                        });
+                    }
+                }
+            }
+        }
+
+        // Hops from the nearest root, found breadth first. Column 0 is the roots themselves.
+        let mut depth: Vec<Option<usize>> = vec![None; heap.size()];
+        let mut queue: VecDeque<usize> = VecDeque::new();
+        for (_, addr) in roots.iter() {
+            if depth[*addr].is_none() {
+                depth[*addr] = Some(1);
+                queue.push_back(*addr);
+            }
+        }
+        while let Some(addr) = queue.pop_front() {
+            for edge in edges.iter().filter(|edge| edge.from == addr) {
+                if depth[edge.to].is_none() {
+                    depth[edge.to] = Some(depth[addr].expect("bug: queued without a depth") + 1);
+                    queue.push_back(edge.to);
+                }
+            }
+        }
+
+        let unreachable_column = depth.iter().flatten().max().map_or(1, |max| max + 1);
+        let mut rows = vec![0; unreachable_column + 1];
+        rows[0] = roots.len();
+
+        let mut nodes = vec![];
+        for (addr, entry) in heap.objects().enumerate() {
+            let is_extern = match entry {
+                HeapValue::Free { .. } => continue,
+                HeapValue::Object(_) => false,
+                HeapValue::Extern(_) => true,
+            };
+
+            let column = depth[addr].unwrap_or(unreachable_column);
+            nodes.push(Node {
+                addr,
+                is_extern,
+                column,
+                row: rows[column],
+            });
+            rows[column] += 1;
+        }
+
+        Self {
+            nodes,
+            edges,
+            roots,
+            columns: unreachable_column + 1,
+            rows: rows.into_iter().max().unwrap_or(0),
+        }
+    }
+
+    /// Draw the graph, with the fields' names on the arrows if `field_names` is set. Clicking
+    /// an object selects it and returns its address, so the caller can toggle whether it's marked.
+    pub fn show(
+        &self,
+        ui: &mut egui::Ui,
+        marked: &[bool],
+        active_object: &mut usize,
+        field_names: bool,
+    ) -> Option<usize> {
+        let size = egui::vec2(
+            self.columns as f32 * COLUMN_WIDTH,
+            self.rows.max(1) as f32 * ROW_HEIGHT,
+        ) + egui::vec2(MARGIN, MARGIN) * 2.0;
+        let (response, painter) = ui.allocate_painter(size, egui::Sense::click());
+        let origin = response.rect.min + egui::vec2(MARGIN, MARGIN);
+
+        let cell = |column: usize, row: usize| {
+            let min = origin + egui::vec2(column as f32 * COLUMN_WIDTH, row as f32 * ROW_HEIGHT);
+            egui::Rect::from_min_size(min, NODE_SIZE)
+        };
+        let node_rect = |addr: usize| {
+            self.nodes
+                .iter()
+                .find(|node| node.addr == addr)
+                .map(|node| cell(node.column, node.row))
+        };
+
+        let font = egui::FontId::monospace(11.0);
+        let arrow = |from: egui::Pos2, to: egui::Pos2, color: egui::Color32, label: &str| {
+            painter.arrow(from, to - from, (1.0, color));
+            if !label.is_empty() {
+                let middle = from + (to - from) * 0.5;
+                painter.text(
+                    middle,
+                    egui::Align2::CENTER_CENTER,
+                    label,
+                    egui::FontId::proportional(11.0),
+                    egui::Color32::LIGHT_GRAY,
+                );
+            }
+        };
+
+        for (row, (name, addr)) in self.roots.iter().enumerate() {
+            let rect = cell(0, row);
+            painter.text(
+                rect.left_center(),
+                egui::Align2::LEFT_CENTER,
+                name,
+                font.clone(),
+                egui::Color32::LIGHT_BLUE,
+            );
+            if let Some(target) = node_rect(*addr) {
+                arrow(
+                    rect.right_center(),
+                    target.left_center(),
+                    egui::Color32::LIGHT_BLUE,
+                    "",
+                );
+            }
+        }
+
+        for edge in self.edges.iter() {
+            if let (Some(from), Some(to)) = (node_rect(edge.from), node_rect(edge.to)) {
+                let label = if field_names { edge.label.as_str() } else { "" };
+                arrow(
+                    from.right_center(),
+                    to.left_center(),
+                    egui::Color32::GRAY,
+                    label,
+                );
+            }
+        }
+
+        for node in self.nodes.iter() {
+            let rect = cell(node.column, node.row);
+            let fill = if marked[node.addr] {
+                egui::Color32::YELLOW.gamma_multiply(0.4)
+            } else {
+                egui::Color32::DARK_GRAY
+            };
+            let stroke = if node.addr == *active_object {
+                egui::Stroke::new(2.0, egui::Color32::WHITE)
+            } else {
+                egui::Stroke::new(1.0, egui::Color32::GRAY)
+            };
+            painter.rect(rect, 0.0, fill, stroke, egui::StrokeKind::Inside);
+
+            let kind = if node.is_extern { "ext" } else { "obj" };
+            painter.text(
+                rect.center(),
+                egui::Align2::CENTER_CENTER,
+                format!("{kind} 0x{:0>6x}", node.addr),
+                font.clone(),
+                egui::Color32::WHITE,
+            );
+        }
+
+        let pos = response
+            .interact_pointer_pos()
+            .filter(|_| response.clicked())?;
+        let node = self
+            .nodes
+            .iter()
+            .find(|node| cell(node.column, node.row).contains(pos))?;
+        *active_object = node.addr;
+        Some(node.addr)
+    }
+}
@@ -0,0 +1,81 @@
+//! Keeping score of how well garbage is being collected.
+
+use super::Difficulty;
+use crate::vm::{Heap, HeapValue};
+
+pub struct GcMetrics {
+    pub total_cycles: usize,
+    pub total_garbage_collected: usize,
+    /// Running totals of every cycle's [`CycleScore`].
+    pub garbage_freed: usize,
+    pub live_freed: usize,
+    pub leaks_kept: usize,
+    /// The level the cycles are being played at.
+    pub difficulty: Difficulty,
+}
+
+impl Default for GcMetrics {
+    fn default() -> Self {
+        Self {
+            total_cycles: 0,
+            total_garbage_collected: 0,
+            garbage_freed: 0,
+            live_freed: 0,
+            leaks_kept: 0,
+            difficulty: Difficulty::default(),
+        }
+    }
+}
+
+impl GcMetrics {
+    pub fn record(&mut self, score: &CycleScore) {
+        self.garbage_freed += score.garbage_freed;
+        self.live_freed += score.live_freed;
+        self.leaks_kept += score.leaks_kept;
+    }
+}
+
+/// How a cycle's decisions compare to what was actually reachable. Free cells don't count.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CycleScore {
+    /// Unreachable objects that were freed, as they should be.
+    pub garbage_freed: usize,
+    /// Reachable objects that were freed. The script will crash if it uses one of these.
+    pub live_freed: usize,
+    /// Unreachable objects that were kept, wasting a cell until a later cycle frees them.
+    pub leaks_kept: usize,
+    /// Reachable objects that were kept, as they should be.
+    pub live_kept: usize,
+}
+
+impl CycleScore {
+    pub fn new(heap: &Heap, marked: &[bool], reachable: &[bool]) -> Self {
+        let mut score = Self::default();
+
+        for (addr, entry) in heap.objects().enumerate() {
+            if let HeapValue::Free { .. } = entry {
+                continue;
+            }
+
+            match (marked[addr], reachable[addr]) {
+                (false, false) => score.garbage_freed += 1,
+                (false, true) => score.live_freed += 1,
+                (true, false) => score.leaks_kept += 1,
+                (true, true) => score.live_kept += 1,
+            }
+        }
+
+        score
+    }
+
+    /// The percentage of objects that were kept or freed correctly.
+    pub fn accuracy(&self) -> f64 {
+        let correct = self.garbage_freed + self.live_kept;
+        let total = correct + self.live_freed + self.leaks_kept;
+        if total == 0 {
+            100.0
+        } else {
+            100.0 * correct as f64 / total as f64
+        }
+    }
+}
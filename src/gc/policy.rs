@@ -0,0 +1,210 @@
+//! Who decides what survives a GC cycle.
+//!
+//! A [`GcPolicy`] only picks the objects to keep; [`run_cycle`] does the sweeping and keeps
+//! [`GcMetrics`](super::GcMetrics) up to date, so every policy is scored the same way. Apart
+//! from the [`ManualGc`](super::ManualGc) game, none of them need a display.
+
+use std::collections::VecDeque;
+
+use super::CycleScore;
+use crate::{Error, vm::Runtime};
+
+pub trait GcPolicy {
+    /// Decide which objects survive this cycle. Returns one entry per heap cell, `true`
+    /// for the ones to keep.
+    fn collect(&mut self, runtime: &mut Runtime) -> Vec<bool>;
+}
+
+/// Run one GC cycle: ask `policy` what to keep, score its choice against what's actually
+/// reachable, and free everything else.
+pub fn run_cycle(policy: &mut dyn GcPolicy, runtime: &mut Runtime) -> CycleScore {
+    let marked = policy.collect(runtime);
+    let score = CycleScore::new(&runtime.heap, &marked, &runtime.trace());
+    let collected = runtime.heap.sweep(&marked);
+
+    let metrics = &mut runtime.gc_metrics;
+    metrics.total_cycles += 1;
+    metrics.total_garbage_collected += collected;
+    metrics.record(&score);
+
+    score
+}
+
+/// Keeps exactly what [`Runtime::trace`] finds reachable.
+pub struct TracingGc;
+
+impl GcPolicy for TracingGc {
+    fn collect(&mut self, runtime: &mut Runtime) -> Vec<bool> {
+        runtime.trace()
+    }
+}
+
+/// Never frees anything, so a script that needs a collection runs out of memory instead.
+pub struct KeepEverything;
+
+impl GcPolicy for KeepEverything {
+    fn collect(&mut self, runtime: &mut Runtime) -> Vec<bool> {
+        vec![true; runtime.heap.size()]
+    }
+}
+
+/// Replays decisions from a file, one line per cycle. Each line lists the addresses to keep,
+/// in decimal or as `0x` hex like the GC window shows them, separated by spaces or commas.
+/// Everything after a `#` is a comment, and lines with nothing but a comment are skipped,
+/// while an empty line frees the whole heap.
+///
+/// Addresses past the end of the heap are ignored. Once the file runs out, every object is
+/// kept, like [`KeepEverything`].
+pub struct ScriptedGc {
+    cycles: VecDeque<Vec<usize>>,
+}
+
+impl ScriptedGc {
+    pub fn load(path: &str) -> Result<Self, Error> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(src: &str) -> Result<Self, Error> {
+        let mut cycles = VecDeque::new();
+
+        for (n, line) in src.lines().enumerate() {
+            let (line, comment) = match line.split_once('#') {
+                Some((line, _)) => (line, true),
+                None => (line, false),
+            };
+            if comment && line.trim().is_empty() {
+                continue;
+            }
+
+            let keep = line
+                .split([' ', '\t', ','])
+                .filter(|word| !word.is_empty())
+                .map(|word| {
+                    let addr = match word.strip_prefix("0x") {
+                        Some(hex) => usize::from_str_radix(hex, 16),
+                        None => word.parse(),
+                    };
+                    addr.map_err(|_| {
+                        Error::InvalidArgument(format!(
+                            "invalid address '{word}' on line {} of GC script",
+                            n + 1
+                        ))
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+            cycles.push_back(keep);
+        }
+
+        Ok(Self { cycles })
+    }
+}
+
+impl GcPolicy for ScriptedGc {
+    fn collect(&mut self, runtime: &mut Runtime) -> Vec<bool> {
+        let size = runtime.heap.size();
+        let Some(keep) = self.cycles.pop_front() else {
+            return vec![true; size];
+        };
+
+        let mut marked = vec![false; size];
+        for addr in keep.into_iter().filter(|addr| *addr < size) {
+            marked[addr] = true;
+        }
+        marked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{HeapConfig, Instruction, Program, RuntimeError, StopReason, Value};
+
+    fn runtime(size: usize) -> Runtime {
+        Runtime::new(HeapConfig {
+            size,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn scripts_parse_one_cycle_per_line() {
+        let gc = ScriptedGc::parse(
+            "# keep the first two\n\
+             0 1\n\
+             0x2a,3\t7 # the rest\n\
+             \n\
+             #\n\
+             12",
+        )
+        .unwrap();
+
+        let cycles: Vec<_> = gc.cycles.into_iter().collect();
+        assert_eq!(cycles, [vec![0, 1], vec![42, 3, 7], vec![], vec![12]]);
+    }
+
+    #[test]
+    fn scripts_reject_bad_addresses() {
+        for src in ["1 two", "0xg", "-1", "1.5", "0 1\n2 3 x # oops"] {
+            let err = ScriptedGc::parse(src).err();
+            assert!(
+                matches!(err, Some(Error::InvalidArgument(_))),
+                "{src}: {err:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn cycles_are_scored_against_what_is_reachable() {
+        let mut runtime = runtime(4);
+        let root = runtime.heap.alloc().unwrap();
+        let child = runtime.heap.alloc().unwrap();
+        let garbage = runtime.heap.alloc().unwrap();
+        runtime
+            .heap
+            .get_mut(root)
+            .unwrap()
+            .set(1, Value::Object(child));
+        runtime.set_global("root", Value::Object(root));
+
+        // Keeps the garbage and frees the child, which the root still points to.
+        let src = format!("{} {}", root.addr, garbage.addr);
+        let mut gc = ScriptedGc::parse(&src).unwrap();
+        let score = run_cycle(&mut gc, &mut runtime);
+
+        assert_eq!(score.live_kept, 1);
+        assert_eq!(score.live_freed, 1);
+        assert_eq!(score.leaks_kept, 1);
+        assert_eq!(score.garbage_freed, 0);
+        assert!(runtime.heap.get(child).is_none());
+        assert_eq!(runtime.gc_metrics.total_cycles, 1);
+        assert_eq!(runtime.gc_metrics.total_garbage_collected, 1);
+        assert_eq!(runtime.gc_metrics.live_freed, 1);
+
+        // The tracer gets it right.
+        let score = run_cycle(&mut TracingGc, &mut runtime);
+        assert_eq!(score.garbage_freed, 1);
+        assert_eq!(score.live_kept, 1);
+        assert_eq!(runtime.gc_metrics.total_garbage_collected, 2);
+    }
+
+    #[test]
+    fn keeping_everything_runs_out_of_memory() {
+        let mut runtime = runtime(1);
+        runtime.gc = Box::new(KeepEverything);
+        let program = Program {
+            code: vec![Instruction::Alloc, Instruction::Alloc, Instruction::Halt],
+            lines: vec![1; 3],
+            constants: vec![],
+            breakpoints: Default::default(),
+        };
+
+        let mut vm = runtime.spawn_vm(&program);
+        assert!(matches!(vm.run(u64::MAX), StopReason::RequestGC));
+        vm.vm.collect_garbage();
+        assert!(matches!(
+            vm.run(u64::MAX),
+            StopReason::Error(RuntimeError::OutOfMemory)
+        ));
+        assert_eq!(vm.vm.gc_metrics.total_garbage_collected, 0);
+    }
+}
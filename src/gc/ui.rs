@@ -1,6 +1,11 @@
+use std::time::Duration;
+
 use egui_sdl2::egui;
 
-use crate::vm::Value;
+use crate::{
+    gc::{CycleScore, Root},
+    vm::{Heap, Interner, Value},
+};
 
 pub fn draw_object_field(ui: &mut egui::Ui, value: Value) {
     let as_u64 = value.to_u64();
@@ -43,6 +48,50 @@ pub fn draw_object_field(ui: &mut egui::Ui, value: Value) {
     }
 }
 
+/// A field as the value it holds: its name, type and contents. Object references are links;
+/// returns the address of the object that was clicked, if any.
+pub fn draw_decoded_field(
+    ui: &mut egui::Ui,
+    name: &str,
+    value: Value,
+    heap: &Heap,
+    interner: &Interner,
+) -> Option<usize> {
+    ui.label(egui::RichText::new(format!(".{name}")).color(egui::Color32::WHITE));
+
+    let (kind, contents, handle) = match value {
+        Value::Nil => ("nil", None, None),
+        Value::Bool(bool) => ("bool", Some(bool.to_string()), None),
+        Value::Number(num) => ("number", Some(num.to_string()), None),
+        Value::String(addr) => ("string", Some(format!("{:?}", interner.get(addr))), None),
+        Value::FunctionPtr(addr) => ("function", Some(format!("fn<{addr}>")), None),
+        Value::Object(handle) => ("object", None, Some(handle)),
+        Value::ExternObject(handle) => ("extern", None, Some(handle)),
+    };
+
+    ui.label(
+        egui::RichText::new(kind)
+            .color(egui::Color32::GRAY)
+            .italics(),
+    );
+    if let Some(contents) = contents {
+        ui.label(egui::RichText::new(contents).color(egui::Color32::LIGHT_GRAY));
+    }
+
+    let handle = handle?;
+    let addr = handle.addr as usize;
+    if !heap.is_live(handle) {
+        ui.label(egui::RichText::new(format!("0x{addr:0>6x} (freed)")).color(egui::Color32::RED));
+        return None;
+    }
+
+    let link = egui::RichText::new(format!("0x{addr:0>6x}")).color(egui::Color32::LIGHT_BLUE);
+    ui.link(link)
+        .on_hover_text("View object")
+        .clicked()
+        .then_some(addr)
+}
+
 pub fn freeing_garbage(ctx: &egui::Context, elapsed: f64, total_time: f64, pause: f64) {
     egui::Window::new("Collecting Garbage")
         .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
@@ -81,3 +130,166 @@ pub fn freeing_garbage(ctx: &egui::Context, elapsed: f64, total_time: f64, pause
             });
         });
 }
+
+/// A dot next to a heap cell saying whether anything can still reach it. Free cells get an
+/// empty space so the rows stay lined up.
+pub fn reachability_hint(ui: &mut egui::Ui, in_use: bool, reachable: bool) {
+    let (color, hint) = match (in_use, reachable) {
+        (false, _) => (egui::Color32::TRANSPARENT, ""),
+        (true, true) => (egui::Color32::GREEN, "Reachable from a root, keep it"),
+        (true, false) => (egui::Color32::RED, "Nothing can reach this, it's garbage"),
+    };
+
+    let dot = ui.label(egui::RichText::new("●").color(color));
+    if in_use {
+        dot.on_hover_text(hint);
+    }
+}
+
+/// How long the player has left to finish the cycle.
+pub fn countdown(ui: &mut egui::Ui, left: Duration) {
+    let color = if left < Duration::from_secs(10) {
+        egui::Color32::RED
+    } else {
+        egui::Color32::WHITE
+    };
+
+    ui.label(
+        egui::RichText::new(format!("⏱ {:.1}s left", left.as_secs_f64()))
+            .color(color)
+            .strong()
+            .size(15.0),
+    );
+}
+
+/// What finishing the cycle is about to do, so the player can think twice. `rooted` lists
+/// the roots that can still reach an object that's about to be freed, directly or through
+/// other objects. Returns `Some(true)` once they confirm and `Some(false)` if they go back.
+pub fn review_cycle(ctx: &egui::Context, freeing: usize, rooted: &[String]) -> Option<bool> {
+    let mut choice = None;
+
+    egui::Window::new("Finish Cycle?")
+        .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+        .title_bar(false)
+        .show(ctx, |ui| {
+            let objects = if freeing == 1 { "object" } else { "objects" };
+            ui.label(
+                egui::RichText::new(format!("{freeing} {objects} will be freed."))
+                    .strong()
+                    .size(15.0),
+            );
+
+            if !rooted.is_empty() {
+                ui.separator();
+                ui.label(
+                    egui::RichText::new("These roots can still reach objects that will be freed:")
+                        .color(egui::Color32::RED),
+                );
+                for root in rooted {
+                    ui.label(egui::RichText::new(root).monospace());
+                }
+            }
+
+            ui.horizontal(|ui| {
+                if ui.button("Free them").clicked() {
+                    choice = Some(true);
+                }
+                if ui.button("Go back").clicked() {
+                    choice = Some(false);
+                }
+            });
+        });
+
+    choice
+}
+
+/// How the player did this cycle. Returns whether they clicked "Continue".
+pub fn cycle_results(ctx: &egui::Context, score: &CycleScore, timed_out: bool) -> bool {
+    let mut resume = false;
+
+    egui::Window::new("Cycle Results")
+        .anchor(egui::Align2::CENTER_CENTER, [0.0, 100.0])
+        .title_bar(false)
+        .show(ctx, |ui| {
+            if timed_out {
+                ui.label(
+                    egui::RichText::new("Time's up!")
+                        .color(egui::Color32::RED)
+                        .strong(),
+                );
+            }
+
+            ui.label(
+                egui::RichText::new(format!("Accuracy: {:.0}%", score.accuracy()))
+                    .strong()
+                    .size(15.0),
+            );
+            ui.separator();
+
+            ui.label(format!("♥ Garbage freed: {}", score.garbage_freed));
+            ui.label(
+                egui::RichText::new(format!("♥ Live objects freed: {}", score.live_freed)).color(
+                    if score.live_freed > 0 {
+                        egui::Color32::RED
+                    } else {
+                        egui::Color32::LIGHT_GRAY
+                    },
+                ),
+            );
+            ui.label(format!("♥ Leaks kept: {}", score.leaks_kept));
+
+            if score.live_freed > 0 {
+                ui.label("Uh oh. The program will SegFault if it touches those.");
+            }
+
+            resume = ui.button("Continue").clicked();
+        });
+
+    resume
+}
+
+/// The globals and stack slots, with the address each one points at. Clicking an address
+/// selects that object.
+pub fn roots_panel(ui: &mut egui::Ui, roots: &[Root], active_object: &mut usize) {
+    ui.vertical(|ui| {
+        ui.set_width(180.0);
+
+        ui.label("Roots");
+        ui.separator();
+
+        if roots.is_empty() {
+            ui.label(egui::RichText::new("<nothing>").color(egui::Color32::LIGHT_GRAY));
+        }
+
+        egui::Grid::new("roots").striped(true).show(ui, |ui| {
+            for root in roots {
+                ui.label(egui::RichText::new(&root.name).color(egui::Color32::WHITE));
+
+                match root.handle {
+                    Some(handle) if root.live => {
+                        let addr = handle.addr as usize;
+                        let text = egui::RichText::new(format!("0x{addr:0>6x}"))
+                            .color(egui::Color32::LIGHT_BLUE);
+                        if ui
+                            .selectable_label(addr == *active_object, text)
+                            .on_hover_text("View object")
+                            .clicked()
+                        {
+                            *active_object = addr;
+                        }
+                    }
+                    Some(handle) => {
+                        ui.label(
+                            egui::RichText::new(format!("0x{:0>6x} (freed)", handle.addr))
+                                .color(egui::Color32::RED),
+                        );
+                    }
+                    None => {
+                        ui.label(egui::RichText::new(&root.text).color(egui::Color32::LIGHT_GRAY));
+                    }
+                }
+                ui.end_row();
+            }
+        });
+    });
+}
@@ -1,3 +1,5 @@
+use std::{iter::Peekable, str::Chars};
+
 use crate::Error;
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
@@ -25,6 +27,98 @@ pub enum TokenKind {
     Do,
     End,
     Alloc,
+
+    // Trivia. These are only produced by `lex_lossless`.
+    Whitespace,
+    Newline,
+    Comment,
+}
+
+impl TokenKind {
+    /// Whitespace and comments. The compiler never sees these, but tooling that needs to
+    /// reproduce the source (formatters, editors) does.
+    pub fn is_trivia(self) -> bool {
+        matches!(
+            self,
+            TokenKind::Whitespace | TokenKind::Newline | TokenKind::Comment
+        )
+    }
+
+    /// Reserved words. These can't be used as variable or field names.
+    pub fn is_keyword(self) -> bool {
+        matches!(
+            self,
+            TokenKind::Nil
+                | TokenKind::True
+                | TokenKind::False
+                | TokenKind::If
+                | TokenKind::Then
+                | TokenKind::Else
+                | TokenKind::ElseIf
+                | TokenKind::While
+                | TokenKind::Do
+                | TokenKind::End
+                | TokenKind::Alloc
+        )
+    }
+}
+
+/// How the block keywords (`IF`, `WHILE`, `END`, ...) must be spelled. The literals `true`,
+/// `false` and `nil` are always lowercase.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum KeywordCase {
+    /// `IF`, `WHILE`, `END`, ...
+    #[default]
+    Upper,
+    /// `if`, `while`, `end`, ...
+    Lower,
+    /// Either of the above. Mixed case spellings like `If` are always identifiers.
+    Any,
+}
+
+impl std::str::FromStr for KeywordCase {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "upper" => Ok(KeywordCase::Upper),
+            "lower" => Ok(KeywordCase::Lower),
+            "any" => Ok(KeywordCase::Any),
+            _ => Err(Error::InvalidArgument(format!(
+                "unknown keyword case '{s}' (expected upper, lower or any)"
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LexerConfig {
+    pub keyword_case: KeywordCase,
+}
+
+impl LexerConfig {
+    fn keyword(&self, word: &str) -> Option<TokenKind> {
+        let is_lowercase = word.bytes().all(|b| b.is_ascii_lowercase());
+        let upper = match self.keyword_case {
+            KeywordCase::Lower | KeywordCase::Any if is_lowercase => word.to_ascii_uppercase(),
+            KeywordCase::Upper | KeywordCase::Any => word.to_string(),
+            KeywordCase::Lower => return None,
+        };
+
+        let kind = match upper.as_str() {
+            "IF" => TokenKind::If,
+            "ELSE" => TokenKind::Else,
+            "ELSEIF" => TokenKind::ElseIf,
+            "THEN" => TokenKind::Then,
+            "WHILE" => TokenKind::While,
+            "DO" => TokenKind::Do,
+            "END" => TokenKind::End,
+            "ALLOC" => TokenKind::Alloc,
+            _ => return None,
+        };
+
+        Some(kind)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -35,200 +129,208 @@ pub struct Token {
     pub line: usize,
 }
 
-pub fn lex<'s>(src: &'s str) -> Result<Vec<Token>, Error> {
+/// Whitespace and comments are kept as trivia tokens. Concatenating the `data` of every token
+/// gives back the exact source text.
+pub fn lex_lossless(src: &str, config: &LexerConfig) -> Result<Vec<Token>, Error> {
+    let mut lexer = Lexer {
+        chars: src.chars().peekable(),
+        col: 0,
+        line: 1,
+    };
+
     let mut tokens = vec![];
 
-    let mut chars = src.chars().peekable();
-    let mut col = 0;
-    let mut line = 1;
-
-    while let Some(char) = chars.next() {
-        let token = match char {
-            '.' => Token {
-                kind: TokenKind::Dot,
-                data: char.to_string(),
-                col,
-                line,
-            },
-            '=' => Token {
-                kind: TokenKind::Equal,
-                data: char.to_string(),
-                col,
-                line,
-            },
-            ';' => Token {
-                kind: TokenKind::Semicolon,
-                data: char.to_string(),
-                col,
-                line,
-            },
-            '(' => Token {
-                kind: TokenKind::LParen,
-                data: char.to_string(),
-                col,
-                line,
-            },
-            ')' => Token {
-                kind: TokenKind::RParen,
-                data: char.to_string(),
-                col,
-                line,
-            },
-            ',' => Token {
-                kind: TokenKind::Comma,
-                data: char.to_string(),
-                col,
-                line,
-            },
-            '-' => Token {
-                kind: TokenKind::Minus,
-                data: char.to_string(),
-                col,
-                line,
-            },
+    while let Some(char) = lexer.chars.next() {
+        let (col, line) = (lexer.col, lexer.line);
+        lexer.col += 1;
+
+        let (kind, data) = match char {
+            '.' => (TokenKind::Dot, char.to_string()),
+            '=' => (TokenKind::Equal, char.to_string()),
+            ';' => (TokenKind::Semicolon, char.to_string()),
+            '(' => (TokenKind::LParen, char.to_string()),
+            ')' => (TokenKind::RParen, char.to_string()),
+            ',' => (TokenKind::Comma, char.to_string()),
+            '-' => (TokenKind::Minus, char.to_string()),
             '\n' => {
-                line += 1;
-                col = 0;
-                continue;
-            }
-            '\r' => {
-                col += 1;
-                continue;
+                lexer.line += 1;
+                lexer.col = 0;
+                (TokenKind::Newline, char.to_string())
             }
-            ' ' => {
-                // Skip whitespace.
-                col += 1;
-                continue;
+            ' ' | '\t' | '\r' => {
+                let data = lexer.take_while(char, |c| matches!(c, ' ' | '\t' | '\r'));
+                (TokenKind::Whitespace, data)
             }
             '♥' => {
-                // Skip single line comments.
-                loop {
-                    if let Some(next_char) = chars.peek() {
-                        match next_char {
-                            '\n' => break,
-                            _ => {
-                                chars.next();
-                                continue;
-                            }
-                        }
-                    }
-                }
-
-                line += 1;
-                continue;
+                // Single line comments run up to (but not including) the next newline.
+                let data = lexer.take_while(char, |c| c != '\n');
+                (TokenKind::Comment, data)
             }
-            c @ '\"' => {
-                let mut str_chars = vec![c];
-                loop {
-                    if let Some(next_char) = chars.next() {
-                        if next_char != '\"' {
-                            str_chars.push(next_char);
-                            // chars.next();
-                            continue;
-                        } else {
-                            str_chars.push(next_char);
-                            // chars.next();
-                            break;
-                        }
+            '\"' => {
+                let mut data = char.to_string();
+                for next_char in lexer.chars.by_ref() {
+                    data.push(next_char);
+                    if next_char == '\n' {
+                        lexer.line += 1;
+                        lexer.col = 0;
                     } else {
-                        break;
+                        lexer.col += 1;
                     }
-                }
 
-                let string = str_chars
-                    .iter()
-                    .map(|c| c.to_string())
-                    .collect::<Vec<String>>()
-                    .join("");
-
-                Token {
-                    kind: TokenKind::String,
-                    data: string,
-                    col,
-                    line,
-                }
-            }
-            c if c.is_ascii_digit() => {
-                let mut id = vec![c];
-                loop {
-                    if let Some(next_char) = chars.peek() {
-                        if next_char.is_ascii_digit() {
-                            id.push(*next_char);
-                            chars.next();
-                            continue;
-                        } else {
-                            break;
-                        }
-                    } else {
+                    if next_char == '\"' {
                         break;
                     }
                 }
 
-                let ident = id
-                    .iter()
-                    .map(|c| c.to_string())
-                    .collect::<Vec<String>>()
-                    .join("");
-
-                Token {
-                    kind: TokenKind::Number,
-                    data: ident,
-                    col,
-                    line,
-                }
+                (TokenKind::String, data)
             }
-            c if c.is_ascii_alphabetic() => {
-                let mut id = vec![c];
-                loop {
-                    if let Some(next_char) = chars.peek() {
-                        if next_char.is_ascii_alphanumeric() || *next_char == '_' {
-                            id.push(*next_char);
-                            chars.next();
-                            continue;
-                        } else {
-                            break;
-                        }
-                    } else {
-                        break;
-                    }
-                }
-
-                let ident = id
-                    .iter()
-                    .map(|c| c.to_string())
-                    .collect::<Vec<String>>()
-                    .join("");
+            c if c.is_ascii_digit() => {
+                let data = lexer.take_while(c, |c| c.is_ascii_digit());
+                (TokenKind::Number, data)
+            }
+            c if unicode_ident::is_xid_start(c) || c == '_' => {
+                let ident = lexer.take_while(c, unicode_ident::is_xid_continue);
 
                 let kind = match ident.as_str() {
                     "true" => TokenKind::True,
                     "false" => TokenKind::False,
                     "nil" => TokenKind::Nil,
-                    "IF" => TokenKind::If,
-                    "ELSE" => TokenKind::Else,
-                    "ELSEIF" => TokenKind::ElseIf,
-                    "THEN" => TokenKind::Then,
-                    "WHILE" => TokenKind::While,
-                    "DO" => TokenKind::Do,
-                    "END" => TokenKind::End,
-                    "ALLOC" => TokenKind::Alloc,
-                    _ => TokenKind::Ident,
+                    word => config.keyword(word).unwrap_or(TokenKind::Ident),
                 };
 
-                Token {
-                    kind,
-                    data: ident,
-                    col,
-                    line,
-                }
+                (kind, ident)
             }
             _ => {
                 return Err(Error::UnexpectedCharacter(char.to_string()));
             }
         };
 
-        col += 1;
-        tokens.push(token);
+        tokens.push(Token {
+            kind,
+            data,
+            col,
+            line,
+        });
     }
 
     Ok(tokens)
 }
+
+struct Lexer<'s> {
+    chars: Peekable<Chars<'s>>,
+    col: usize,
+    line: usize,
+}
+
+impl Lexer<'_> {
+    /// Collect `first` and every following character that matches `pred`. None of the
+    /// characters may be a newline.
+    fn take_while(&mut self, first: char, pred: impl Fn(char) -> bool) -> String {
+        let mut data = first.to_string();
+        while let Some(next_char) = self.chars.next_if(|c| pred(*c)) {
+            data.push(next_char);
+            self.col += 1;
+        }
+
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(src: &str, keyword_case: KeywordCase) -> Vec<TokenKind> {
+        lex_lossless(src, &LexerConfig { keyword_case })
+            .unwrap()
+            .into_iter()
+            .map(|token| token.kind)
+            .filter(|kind| !kind.is_trivia())
+            .collect()
+    }
+
+    #[test]
+    fn keyword_case_picks_the_spellings() {
+        use TokenKind::{End, Ident, If, Nil, True};
+
+        let src = "IF if If END end nil true";
+        assert_eq!(
+            kinds(src, KeywordCase::Upper),
+            [If, Ident, Ident, End, Ident, Nil, True]
+        );
+        assert_eq!(
+            kinds(src, KeywordCase::Lower),
+            [Ident, If, Ident, Ident, End, Nil, True]
+        );
+        assert_eq!(
+            kinds(src, KeywordCase::Any),
+            [If, If, Ident, End, End, Nil, True]
+        );
+    }
+
+    #[test]
+    fn identifiers_are_xid() {
+        let tokens = lex_lossless("größe = _x1; 名前.ñ2", &LexerConfig::default()).unwrap();
+        let idents: Vec<_> = tokens
+            .iter()
+            .filter(|token| token.kind == TokenKind::Ident)
+            .map(|token| token.data.as_str())
+            .collect();
+        assert_eq!(idents, ["größe", "_x1", "名前", "ñ2"]);
+
+        // Identifiers can't start with a digit or a combining mark.
+        let tokens = lex_lossless("1x", &LexerConfig::default()).unwrap();
+        assert_eq!(tokens[0].kind, TokenKind::Number);
+        let tokens = lex_lossless("\u{301}x", &LexerConfig::default());
+        assert!(matches!(tokens, Err(Error::UnexpectedCharacter(c)) if c == "\u{301}"));
+    }
+
+    #[test]
+    fn trivia_round_trips() {
+        for src in [
+            "",
+            "x = 1;",
+            "  \tx=1 ;\r\n\n",
+            "♥ just a comment",
+            "IF x THEN ♥ why\n  print(\"a ♥ b\");\nEND\n",
+            "s = \"two\nlines\";  ♥ trailing",
+        ] {
+            let tokens = lex_lossless(src, &LexerConfig::default()).unwrap();
+            let text: String = tokens.iter().map(|token| token.data.as_str()).collect();
+            assert_eq!(text, src);
+        }
+    }
+
+    #[test]
+    fn comment_at_the_end_of_the_file() {
+        let tokens = lex_lossless("x = 1; ♥ no newline", &LexerConfig::default()).unwrap();
+        let last = tokens.last().unwrap();
+        assert_eq!(last.kind, TokenKind::Comment);
+        assert_eq!(last.data, "♥ no newline");
+    }
+
+    #[test]
+    fn lines_and_columns() {
+        let src = "a = 1;\n♥ note\n\ns = \"x\ny\"; b\n";
+        let tokens = lex_lossless(src, &LexerConfig::default()).unwrap();
+        let positions: Vec<_> = tokens
+            .iter()
+            .filter(|token| !token.kind.is_trivia())
+            .map(|token| (token.data.as_str(), token.line, token.col))
+            .collect();
+        assert_eq!(
+            positions,
+            [
+                ("a", 1, 0),
+                ("=", 1, 2),
+                ("1", 1, 4),
+                (";", 1, 5),
+                ("s", 4, 0),
+                ("=", 4, 2),
+                ("\"x\ny\"", 4, 4),
+                (";", 5, 2),
+                ("b", 5, 4),
+            ]
+        );
+    }
+}
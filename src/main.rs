@@ -1,14 +1,34 @@
-use std::{env, io::Write, time::Duration};
+use std::{
+    env,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    gc::gc_app, lexer::{Token, TokenKind}, vm::{Runtime, Value}
+    compiler::Module,
+    gc::{Difficulty, GcMode},
+    lexer::{LexerConfig, Token, TokenKind},
+    vm::{
+        HeapConfig, Runtime, RuntimeError, StopReason, Value,
+        regvm::{self, RegVm},
+    },
 };
 
+mod ast;
+mod bytecode;
 mod compiler;
+mod disasm;
+mod gc;
 mod lexer;
+mod optimizer;
+mod parser;
 mod sdl;
+mod syntax;
+mod verifier;
 mod vm;
-mod gc;
 
 #[derive(Debug)]
 pub enum Error {
@@ -17,10 +37,109 @@ pub enum Error {
     UnexpectedEOF,
     UnexpectedEOFExpected(TokenKind),
     UnexpectedTokenExpected(TokenKind, TokenKind),
+    /// A reserved word was used as a variable or field name.
+    KeywordAsIdentifier(Token),
+    /// Bad command line argument.
+    InvalidArgument(String),
+    Io(std::io::Error),
+    /// A `.nacb` file is corrupt or was made by an incompatible version.
+    InvalidBytecode(String),
+    /// A module failed [`verifier::verify`]. `addr` is the offending instruction.
+    VerifyFailed {
+        addr: usize,
+        reason: String,
+    },
+    /// A script failed while running. `line` is 0 if the module has no line table.
+    Runtime {
+        error: RuntimeError,
+        line: usize,
+    },
+    /// A script didn't finish within this many instructions.
+    OutOfFuel(u64),
 }
 
-fn main() -> Result<(), Error> {
-    let mut runtime = Runtime::new();
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// Command line options. Flags look like `--name=value` and can go anywhere, everything
+/// else ends up in `args`.
+#[derive(Default)]
+struct Options {
+    lexer: LexerConfig,
+    /// Leave the line table out of compiled `.nacb` files.
+    strip_lines: bool,
+    /// Skip the [optimizer] so the bytecode matches the source one to one.
+    no_opt: bool,
+    /// Stop scripts after this many instructions.
+    fuel: Option<u64>,
+    /// `--heap=<objects>`, `--max-heap=<objects>` and `--heap-growth=<percent>`. The initial
+    /// size defaults to the difficulty's.
+    heap: HeapConfig,
+    /// `--difficulty=easy|normal|hard|nightmare`.
+    difficulty: Difficulty,
+    /// `--gc=manual|assisted|automatic|keep|script:<file>`.
+    gc: GcMode,
+    args: Vec<String>,
+}
+
+impl Options {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Self, Error> {
+        let mut options = Options::default();
+        let mut heap_size = None;
+
+        for arg in args {
+            match arg.split_once('=') {
+                Some(("--keywords", case)) => options.lexer.keyword_case = case.parse()?,
+                Some(("--gc", mode)) => options.gc = mode.parse()?,
+                Some(("--difficulty", level)) => options.difficulty = level.parse()?,
+                Some(("--fuel", fuel)) => options.fuel = Some(parse_flag("fuel", fuel)?),
+                Some(("--heap", size)) => heap_size = Some(parse_heap_size("heap size", size)?),
+                Some(("--max-heap", size)) => {
+                    options.heap.max_size = parse_heap_size("max heap size", size)?
+                }
+                Some(("--heap-growth", percent)) => {
+                    let percent: f64 = parse_flag("heap growth", percent)?;
+                    if percent.is_nan() || percent < 0.0 {
+                        return Err(Error::InvalidArgument(format!(
+                            "invalid heap growth '{percent}'"
+                        )));
+                    }
+                    options.heap.grow_below = Some(percent / 100.0);
+                }
+                None if arg == "--strip-lines" => options.strip_lines = true,
+                None if arg == "--no-opt" => options.no_opt = true,
+                _ if arg.starts_with("--") => {
+                    return Err(Error::InvalidArgument(format!("unknown flag '{arg}'")));
+                }
+                _ => options.args.push(arg),
+            }
+        }
+
+        options.heap.size = heap_size.unwrap_or(options.difficulty.heap_size());
+
+        Ok(options)
+    }
+}
+
+fn parse_flag<T: FromStr>(name: &str, value: &str) -> Result<T, Error> {
+    value
+        .parse()
+        .map_err(|_| Error::InvalidArgument(format!("invalid {name} '{value}'")))
+}
+
+/// A heap can't have room for zero objects: nothing could ever be allocated.
+fn parse_heap_size(name: &str, value: &str) -> Result<usize, Error> {
+    match parse_flag(name, value)? {
+        0 => Err(Error::InvalidArgument(format!("{name} must be at least 1"))),
+        size => Ok(size),
+    }
+}
+
+/// The functions every script can call, apart from the SDL ones.
+fn register_builtins(runtime: &mut Runtime) {
     runtime.register_function("print", 1, |args| {
         let value = args.stack.pop().expect("missing arg");
 
@@ -35,11 +154,11 @@ fn main() -> Result<(), Error> {
             Value::FunctionPtr(idx) => println!("fn<{idx}>"),
             Value::Object(idx) => match args.heap.get(idx) {
                 Some(obj) => println!("{obj:?}"),
-                None => println!("Object {{ <oops.__{idx}> }}"),
+                None => println!("Object {{ <oops.__{}> }}", idx.addr),
             },
             Value::ExternObject(addr) => match args.heap.get_extern(addr) {
                 Some(obj) => println!("{obj:?}"),
-                None => println!("ExternObject {{ <oops.__{addr}> }}"),
+                None => println!("ExternObject {{ <oops.__{}> }}", addr.addr),
             },
         }
 
@@ -215,14 +334,10 @@ fn main() -> Result<(), Error> {
             let str = args.strings.get(addr);
             Value::Number(str.len() as f64)
         }
-        Value::Object(addr) => {
-            let obj = args.heap.get(addr);
-            if let Some(obj) = obj {
-                Value::Number(obj.data.len() as f64)
-            } else {
-                todo!("real errors. This is a segfault");
-            }
-        }
+        Value::Object(addr) => match args.heap.get(addr) {
+            Some(obj) => Value::Number(obj.data.len() as f64),
+            None => args.segfault(addr),
+        },
         v => panic!("Expected string or object, got {:?}", v),
     });
 
@@ -231,7 +346,15 @@ fn main() -> Result<(), Error> {
         std::thread::sleep(Duration::from_secs_f64(duration));
         Value::Nil
     });
+}
+
+fn main() -> Result<(), Error> {
+    let options = Options::parse(env::args().skip(1))?;
 
+    let mut runtime = Runtime::new(options.heap);
+    runtime.gc_metrics.difficulty = options.difficulty;
+    runtime.gc = options.gc.policy()?;
+    register_builtins(&mut runtime);
     sdl::register_sdl_functions(&mut runtime);
 
     pub enum Mode {
@@ -240,12 +363,60 @@ fn main() -> Result<(), Error> {
     }
 
     let mut mode = Mode::Normal;
+    if options.args.first().is_some_and(|arg| arg == "compile") {
+        // nac compile <file.nac> [out.nacb]
+        let Some(input) = options.args.get(1) else {
+            return Err(Error::InvalidArgument(
+                "usage: compile <file.nac> [out.nacb]".into(),
+            ));
+        };
+
+        let output = match options.args.get(2) {
+            Some(output) => output.into(),
+            None => Path::new(input).with_extension("nacb"),
+        };
+
+        let src = std::fs::read_to_string(input)?;
+        let module = compile_source(&src, &options)?;
+
+        let mut file = BufWriter::new(File::create(&output)?);
+        bytecode::save(&module, &mut file, !options.strip_lines)?;
+        file.flush()?;
+
+        println!("Wrote {}", output.display());
+    } else if options.args.first().is_some_and(|arg| arg == "disasm") {
+        // nac disasm <file.nac|file.nacb>
+        let Some(input) = options.args.get(1) else {
+            return Err(Error::InvalidArgument(
+                "usage: disasm <file.nac|file.nacb>".into(),
+            ));
+        };
 
-    let args: Vec<String> = env::args().collect();
-    if let Some(path) = args.get(1) {
-        let src = std::fs::read_to_string(path).expect("error reading file");
+        let module = load_module(input, &options)?;
+        print!(
+            "{}",
+            disasm::disassemble(&module, read_source(input)?.as_deref())
+        );
+    } else if options.args.first().is_some_and(|arg| arg == "bench") {
+        // nac bench <file.nac>...
+        if options.args.len() < 2 {
+            return Err(Error::InvalidArgument("usage: bench <file.nac>...".into()));
+        }
 
-        run(src, &mut runtime)?;
+        // Build with and without `--features nan-boxing` to compare value representations.
+        let repr = if cfg!(feature = "nan-boxing") {
+            "NaN-boxed"
+        } else {
+            "enum"
+        };
+        println!("values: {repr}, {} bytes", size_of::<vm::Slot>());
+
+        for path in options.args[1..].iter() {
+            bench(path, &options, &mut runtime)?;
+        }
+    } else if let Some(path) = options.args.first() {
+        let module = load_module(path, &options)?;
+        run_module(&module, &mut runtime, options.fuel)?;
     } else {
         println!("♥ Welcome to Nuclear Alabaster Chainsaw - v0.0.1 ♥");
         println!("(Type ':exit' to quit)\n");
@@ -271,7 +442,11 @@ fn main() -> Result<(), Error> {
                         mode = Mode::Normal;
                     }
                     _ => match mode {
-                        Mode::Normal => run(line, &mut runtime)?,
+                        Mode::Normal => {
+                            if let Err(err) = run(line, &mut runtime, &options) {
+                                println!("Error: {err:?}");
+                            }
+                        }
                         Mode::Debug => {}
                     },
                 },
@@ -301,20 +476,112 @@ fn main() -> Result<(), Error> {
 
                         let mut vm = runtime.spawn_vm(module);
 
-                        println!("{:<10}{:?}", vm.vm.ip, vm.module.code[vm.vm.ip]);
+                        let ip = vm.vm.ip;
+                        println!(
+                            "{ip:<10}{:<6}{:?}",
+                            vm.program.lines[ip], vm.program.code[ip]
+                        );
 
                         match vm.step() {
-                            vm::ControlFlow::RequestGC => {
+                            Ok(vm::ControlFlow::RequestGC) => {
                                 println!("GC requested");
                                 vm_halted = true;
                             }
-                            vm::ControlFlow::Continue => {}
-                            vm::ControlFlow::Halt => vm_halted = true,
+                            Ok(vm::ControlFlow::Continue) => {}
+                            Ok(vm::ControlFlow::Halt) => vm_halted = true,
+                            Err(err) => {
+                                println!("Error: {err:?}");
+                                vm_halted = true;
+                            }
+                        }
+                    }
+                    l if l.starts_with(":run") => {
+                        let Some(module) = &module else {
+                            println!("Please load a file first");
+                            continue;
+                        };
+
+                        if vm_halted {
+                            println!("VM halted");
+                            continue;
+                        }
+
+                        let fuel = match l.split(" ").nth(1).map(str::parse) {
+                            None => REPL_FUEL,
+                            Some(Ok(fuel)) => fuel,
+                            Some(Err(_)) => {
+                                println!("Please provide a number of instructions");
+                                continue;
+                            }
+                        };
+
+                        let mut vm = runtime.spawn_vm(module);
+                        let reason = vm.run(fuel);
+                        let ip = vm.vm.ip;
+                        match reason {
+                            StopReason::Halt => {
+                                println!("Halted after {} instructions", vm.steps);
+                                vm_halted = true;
+                            }
+                            StopReason::OutOfFuel => {
+                                println!("Ran {} instructions, stopped at {ip}", vm.steps);
+                            }
+                            StopReason::RequestGC => {
+                                println!("GC requested at {ip}");
+                                vm_halted = true;
+                            }
+                            StopReason::Error(err) => {
+                                println!("Error at {ip} (line {}): {err:?}", module.lines[ip]);
+                                vm_halted = true;
+                            }
+                            StopReason::Breakpoint(addr) => println!("Breakpoint at {addr}"),
+                        }
+                    }
+                    l if l.starts_with(":break") => {
+                        let Some(module) = &mut module else {
+                            println!("Please load a file first");
+                            continue;
+                        };
+
+                        let Some(Ok(addr)) = l.split(" ").nth(1).map(str::parse::<usize>) else {
+                            println!("Please provide an address");
+                            continue;
+                        };
+
+                        if addr >= module.code.len() {
+                            println!("No instruction at {addr}");
+                        } else if module.breakpoints.remove(&addr) {
+                            println!("Removed breakpoint at {addr}");
+                        } else {
+                            module.breakpoints.insert(addr);
+                            println!("Added breakpoint at {addr}");
                         }
                     }
                     l if l.starts_with(":load") => {
                         let Some(path) = l.split(" ").nth(1) else {
-                            println!("Pease provide a path");
+                            println!("Please provide a path");
+                            continue;
+                        };
+
+                        let new_module = match load_module(path, &options) {
+                            Ok(module) => module,
+                            Err(err) => {
+                                println!("Error loading file: {:?}", err);
+                                continue;
+                            }
+                        };
+
+                        let source = read_source(path).ok().flatten();
+                        println!("=== {path} ===");
+                        print!("{}", disasm::disassemble(&new_module, source.as_deref()));
+
+                        runtime.reset();
+                        module = Some(runtime.load(&new_module));
+                        vm_halted = false;
+                    }
+                    l if l.starts_with(":cst") => {
+                        let Some(path) = l.split(" ").nth(1) else {
+                            println!("Please provide a path");
                             continue;
                         };
 
@@ -326,15 +593,17 @@ fn main() -> Result<(), Error> {
                             }
                         };
 
-                        let tokens = lexer::lex(&src)?;
-                        let new_module = compiler::compile(tokens, &mut runtime)?;
-                        println!("=== {path} ===");
-                        for (addr, inst) in new_module.code.iter().enumerate() {
-                            println!("{addr:<10}   {inst:?}");
+                        let tree = match syntax::parse(&src, &options.lexer) {
+                            Ok(tree) => tree,
+                            Err(err) => {
+                                println!("Error parsing file: {:?}", err);
+                                continue;
+                            }
+                        };
+                        print!("{}", tree.dump());
+                        if tree.to_string() != src {
+                            println!("bug: syntax tree does not round-trip {path}");
                         }
-
-                        module = Some(new_module);
-                        vm_halted = false;
                     }
                     l if l.starts_with(":get") => {
                         let Some(name) = l.split(" ").nth(1) else {
@@ -360,7 +629,7 @@ fn main() -> Result<(), Error> {
                     _ => println!("Please enter a valid command"),
                 },
             }
-            
+
             // Reset line start.
             print!("> ");
             std::io::stdout().flush().unwrap();
@@ -370,35 +639,177 @@ fn main() -> Result<(), Error> {
     Ok(())
 }
 
-fn run(src: String, runtime: &mut Runtime) -> Result<(), Error> {
-    let tokens = lexer::lex(&src)?;
+/// Compile a script. Like [`bytecode::load`], this [verifies](verifier::verify) the module, so
+/// everything handed to the runtime has been verified exactly once.
+fn compile_source(src: &str, options: &Options) -> Result<Module, Error> {
+    let program = parser::parse(src, &options.lexer)?;
+    let mut module = compiler::compile(&program)?;
+
+    if !options.no_opt {
+        optimizer::optimize(&mut module);
+    }
 
-    // for token in tokens.iter() {
-    //     println!("{token:?}");
-    // }
+    verifier::verify(&module)?;
 
-    let module = compiler::compile(tokens, runtime)?;
-    println!("=== MODULE ===");
-    for (addr, inst) in module.code.iter().enumerate() {
-        println!("{addr:<10}{inst:?}");
+    Ok(module)
+}
+
+fn is_bytecode(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|ext| ext == "nacb")
+}
+
+/// The source text of `path`, or `None` if it's a bytecode file.
+fn read_source(path: &str) -> Result<Option<String>, Error> {
+    if is_bytecode(path) {
+        Ok(None)
+    } else {
+        Ok(Some(std::fs::read_to_string(path)?))
     }
+}
+
+/// Load a module from either a source (`.nac`) or bytecode (`.nacb`) file.
+fn load_module(path: &str, options: &Options) -> Result<Module, Error> {
+    if is_bytecode(path) {
+        let mut file = BufReader::new(File::open(path)?);
+        bytecode::load(&mut file)
+    } else {
+        let src = std::fs::read_to_string(path)?;
+        compile_source(&src, options)
+    }
+}
+
+/// How many instructions a line typed into the REPL may run, unless `--fuel` says otherwise.
+/// Enough for anything reasonable, and it means `WHILE true DO END` doesn't hang the REPL.
+const REPL_FUEL: u64 = 100_000_000;
+
+fn run(src: String, runtime: &mut Runtime, options: &Options) -> Result<(), Error> {
+    let module = compile_source(&src, options)?;
+    run_module(&module, runtime, Some(options.fuel.unwrap_or(REPL_FUEL)))
+}
+
+/// Run a module to completion, collecting garbage with the runtime's policy whenever it asks.
+/// With `fuel`, it's stopped with [`Error::OutOfFuel`] after that many instructions.
+fn run_module(module: &Module, runtime: &mut Runtime, fuel: Option<u64>) -> Result<(), Error> {
+    println!("=== MODULE ===");
+    print!("{}", disasm::disassemble(module, None));
     println!("");
 
-    let mut vm = runtime.spawn_vm(&module);
+    let program = runtime.load(module);
+    let mut vm = runtime.spawn_vm(&program);
+    let fuel = fuel.unwrap_or(u64::MAX);
 
-    loop {
-        match vm.step() {
-            vm::ControlFlow::Continue => continue,
-            vm::ControlFlow::Halt => break,
-            vm::ControlFlow::RequestGC => {
+    let result = loop {
+        match vm.run(fuel - vm.steps) {
+            StopReason::Halt => break Ok(()),
+            StopReason::RequestGC => {
                 println!("Garbage collection triggered");
 
-                gc_app(vm.vm);
+                vm.vm.collect_garbage();
             }
+            StopReason::OutOfFuel => break Err(Error::OutOfFuel(fuel)),
+            StopReason::Error(error) => {
+                let line = program.lines[vm.vm.ip];
+                break Err(Error::Runtime { error, line });
+            }
+            StopReason::Breakpoint(_) => unreachable!("bug: breakpoint in a fresh program"),
         }
+    };
+
+    runtime.reset();
+
+    result
+}
+
+/// Run a script on the stack VM once without and once with the optimizer, and then on the
+/// register VM prototype. Reports how many instructions each executed and how long it took.
+fn bench(path: &str, options: &Options, runtime: &mut Runtime) -> Result<(), Error> {
+    let src = std::fs::read_to_string(path)?;
+
+    println!("{path}");
+
+    let mut baseline: Option<Duration> = None;
+    let mut report = |name: &str, instructions: usize, steps: u64, elapsed: Duration| {
+        print!("  {name:<12}{instructions:>6} instructions{steps:>10} steps{elapsed:>12.2?}");
+        match baseline {
+            Some(baseline) => println!(
+                "   {:.2}x faster",
+                baseline.as_secs_f64() / elapsed.as_secs_f64()
+            ),
+            None => {
+                println!();
+                baseline = Some(elapsed);
+            }
+        }
+    };
+
+    for no_opt in [true, false] {
+        let options = Options {
+            lexer: options.lexer,
+            no_opt,
+            ..Default::default()
+        };
+        let module = compile_source(&src, &options)?;
+
+        let program = runtime.load(&module);
+        let mut vm = runtime.spawn_vm(&program);
+
+        // GC cycles aren't timed, since the manual GC waits for the player.
+        let mut elapsed = Duration::ZERO;
+        let reason = loop {
+            let start = Instant::now();
+            let reason = vm.run(u64::MAX);
+            elapsed += start.elapsed();
+
+            match reason {
+                StopReason::RequestGC => {
+                    vm.vm.collect_garbage();
+                }
+                reason => break reason,
+            }
+        };
+        let steps = vm.steps;
+        let ip = vm.vm.ip;
+        runtime.reset();
+
+        match reason {
+            StopReason::Halt => {}
+            StopReason::Error(error) => {
+                let line = program.lines[ip];
+                return Err(Error::Runtime { error, line });
+            }
+            StopReason::RequestGC | StopReason::OutOfFuel | StopReason::Breakpoint(_) => {
+                unreachable!("bug: benchmark stopped early")
+            }
+        }
+
+        let name = if no_opt { "unoptimized" } else { "optimized" };
+        report(name, module.code.len(), steps, elapsed);
     }
 
+    let module = regvm::compile(&parser::parse(&src, &options.lexer)?)?;
+    let program = regvm::load(runtime, &module);
+    let mut vm = RegVm::new(runtime, &program);
+
+    let mut elapsed = Duration::ZERO;
+    loop {
+        let start = Instant::now();
+        let flow = vm.run();
+        elapsed += start.elapsed();
+
+        match flow {
+            Ok(vm::ControlFlow::Halt) => break,
+            Ok(vm::ControlFlow::RequestGC) => {
+                vm.collect_garbage();
+            }
+            Ok(vm::ControlFlow::Continue) => unreachable!("bug: RegVm::run returned Continue"),
+            // Register modules have no line table.
+            Err(error) => return Err(Error::Runtime { error, line: 0 }),
+        }
+    }
+    let steps = vm.steps;
     runtime.reset();
 
+    report("register", module.code.len(), steps, elapsed);
+
     Ok(())
 }
@@ -0,0 +1,380 @@
+//! Bytecode optimizations, run on a [`Module`] straight after it's compiled.
+//!
+//! - Branches on a constant are folded: `LoadTrue; JmpIfFalse` disappears, and
+//!   `LoadFalse; JmpIfFalse` becomes an unconditional `Jmp`.
+//! - Values that are pushed and immediately popped without side effects (`5;`, `x;`) are
+//!   removed.
+//! - Jumps to the next instruction are removed.
+//! - Code that can't be reached from the start (like the body of `IF false THEN ... END` once
+//!   its branch is folded) is removed.
+//!
+//! Removing instructions shifts everything after them, so every relative jump is fixed up to
+//! point at the same instruction as before. Each of these can enable the others, so they're
+//! repeated until nothing changes.
+//!
+//! Arithmetic and comparisons aren't folded. They're calls to builtins like `add` and `lt`,
+//! which are globals that a script (or another module loaded into the same runtime) can
+//! reassign, so `add(1, 2)` isn't known to be `3` until it runs.
+//!
+//! Finally, common instruction pairs are fused into superinstructions, saving a dispatch (and
+//! a push and pop) each:
+//!
+//! | Sequence            | Superinstruction  | Comes from             |
+//! |---------------------|-------------------|------------------------|
+//! | `Load`, `IndexGet`  | `LoadGlobalField` | `player.x`             |
+//! | `IndexSet`, `Pop`   | `IndexSetPop`     | `player.x = 1;`        |
+//! | `Call`, `Pop`       | `CallPop`         | `draw(player);`        |
+
+use crate::{
+    compiler::{Constant, Module},
+    vm::Instruction,
+};
+
+pub fn optimize(module: &mut Module) {
+    loop {
+        let folded = fold(module);
+        let eliminated = eliminate_dead_code(module);
+        if !folded && !eliminated {
+            break;
+        }
+    }
+
+    fuse(module);
+}
+
+/// Replace instruction pairs with the equivalent superinstruction.
+fn fuse(module: &mut Module) {
+    let targets = jump_targets(&module.code);
+    let mut removed = vec![false; module.code.len()];
+
+    let mut addr = 0;
+    while addr + 1 < module.code.len() {
+        // Nothing may jump to the second instruction of the pair, since it's going away.
+        let fused = match (module.code[addr], module.code[addr + 1]) {
+            _ if targets[addr + 1] => None,
+            (Instruction::Load { index: global }, Instruction::IndexGet { index: field }) => {
+                Some(Instruction::LoadGlobalField { global, field })
+            }
+            (Instruction::IndexSet { index }, Instruction::Pop) => {
+                Some(Instruction::IndexSetPop { index })
+            }
+            (Instruction::Call { args }, Instruction::Pop) => Some(Instruction::CallPop { args }),
+            _ => None,
+        };
+
+        match fused {
+            Some(inst) => {
+                module.code[addr] = inst;
+                removed[addr + 1] = true;
+                addr += 2;
+            }
+            None => addr += 1,
+        }
+    }
+
+    remove(module, &removed);
+}
+
+/// Peephole rewrites of instruction pairs. Returns whether anything changed.
+fn fold(module: &mut Module) -> bool {
+    let targets = jump_targets(&module.code);
+    let mut removed = vec![false; module.code.len()];
+
+    let mut addr = 0;
+    while addr < module.code.len() {
+        let inst = module.code[addr];
+
+        if let Instruction::Jmp { addr: 0 } = inst {
+            removed[addr] = true;
+            addr += 1;
+            continue;
+        }
+
+        // Pairs can only be folded if nothing jumps between the two instructions.
+        let Some(next) = module.code.get(addr + 1).copied() else {
+            break;
+        };
+        if targets[addr + 1] {
+            addr += 1;
+            continue;
+        }
+
+        match (truthiness(module, inst), next) {
+            (Some(true), Instruction::JmpIfFalse { .. }) => {
+                removed[addr] = true;
+                removed[addr + 1] = true;
+                addr += 2;
+                continue;
+            }
+            (Some(false), Instruction::JmpIfFalse { addr: offset }) => {
+                removed[addr] = true;
+                module.code[addr + 1] = Instruction::Jmp { addr: offset };
+                addr += 2;
+                continue;
+            }
+            _ => {}
+        }
+
+        if is_pure_push(inst) && matches!(next, Instruction::Pop) {
+            removed[addr] = true;
+            removed[addr + 1] = true;
+            addr += 2;
+            continue;
+        }
+
+        addr += 1;
+    }
+
+    remove(module, &removed)
+}
+
+/// Remove every instruction that can't be reached from the start of the module. Returns
+/// whether anything was removed.
+fn eliminate_dead_code(module: &mut Module) -> bool {
+    let len = module.code.len();
+    let mut reachable = vec![false; len];
+    let mut worklist = vec![0];
+
+    while let Some(addr) = worklist.pop() {
+        if addr >= len || reachable[addr] {
+            continue;
+        }
+        reachable[addr] = true;
+
+        match module.code[addr] {
+            Instruction::Halt => {}
+            Instruction::Jmp { addr: offset } => worklist.push(jump_target(addr, offset)),
+            Instruction::JmpIfFalse { addr: offset } => {
+                worklist.push(addr + 1);
+                worklist.push(jump_target(addr, offset));
+            }
+            _ => worklist.push(addr + 1),
+        }
+    }
+
+    // The final `Halt` stays even if it's unreachable (after an endless loop, say), since
+    // every module has to end in one.
+    let removed: Vec<bool> = reachable
+        .iter()
+        .enumerate()
+        .map(|(addr, reachable)| !reachable && addr != len - 1)
+        .collect();
+
+    remove(module, &removed)
+}
+
+/// Drop the `removed` instructions and fix up the jumps around them. A jump to a removed
+/// instruction goes to the next one that's kept instead. Returns whether anything was removed.
+fn remove(module: &mut Module, removed: &[bool]) -> bool {
+    if !removed.contains(&true) {
+        return false;
+    }
+
+    // `new_addrs[addr]` is where the first kept instruction at or after `addr` ends up.
+    let mut new_addrs = Vec::with_capacity(removed.len() + 1);
+    let mut kept = 0;
+    for removed in removed.iter() {
+        new_addrs.push(kept);
+        if !removed {
+            kept += 1;
+        }
+    }
+    new_addrs.push(kept);
+
+    let relocate = |addr: usize, offset: i32| {
+        let target = new_addrs[jump_target(addr, offset)];
+        target as i32 - new_addrs[addr] as i32 - 1
+    };
+
+    let mut code = Vec::with_capacity(kept);
+    let mut lines = Vec::with_capacity(kept);
+    for (addr, inst) in module.code.iter().enumerate() {
+        if removed[addr] {
+            continue;
+        }
+
+        code.push(match *inst {
+            Instruction::Jmp { addr: offset } => Instruction::Jmp {
+                addr: relocate(addr, offset),
+            },
+            Instruction::JmpIfFalse { addr: offset } => Instruction::JmpIfFalse {
+                addr: relocate(addr, offset),
+            },
+            inst => inst,
+        });
+        lines.push(module.lines[addr]);
+    }
+
+    module.code = code;
+    module.lines = lines;
+
+    true
+}
+
+/// Whether the value pushed by `inst` is known at compile time to be truthy or falsy.
+fn truthiness(module: &Module, inst: Instruction) -> Option<bool> {
+    match inst {
+        Instruction::LoadNil | Instruction::LoadFalse => Some(false),
+        Instruction::LoadTrue => Some(true),
+        Instruction::LoadConst { index } => match module.constants[index as usize] {
+            // Only `nil` and `false` are falsy.
+            Constant::Number(_) | Constant::String(_) => Some(true),
+        },
+        _ => None,
+    }
+}
+
+/// Instructions that push a value and do nothing else.
+fn is_pure_push(inst: Instruction) -> bool {
+    matches!(
+        inst,
+        Instruction::Load { .. }
+            | Instruction::LoadNil
+            | Instruction::LoadTrue
+            | Instruction::LoadFalse
+            | Instruction::LoadConst { .. }
+    )
+}
+
+fn jump_targets(code: &[Instruction]) -> Vec<bool> {
+    let mut targets = vec![false; code.len() + 1];
+    for (addr, inst) in code.iter().enumerate() {
+        if let Instruction::Jmp { addr: offset } | Instruction::JmpIfFalse { addr: offset } = *inst
+        {
+            targets[jump_target(addr, offset)] = true;
+        }
+    }
+    targets
+}
+
+/// Jumps are relative to the instruction following the jump.
+fn jump_target(addr: usize, offset: i32) -> usize {
+    (addr as i64 + 1 + offset as i64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compiler,
+        lexer::LexerConfig,
+        parser,
+        vm::{HeapConfig, Runtime, StopReason},
+    };
+    use Instruction::*;
+
+    fn module(code: Vec<Instruction>) -> Module {
+        Module {
+            lines: (0..code.len()).collect(),
+            code,
+            constants: vec![],
+            globals: vec!["x".to_string()],
+            fields: vec!["y".to_string()],
+        }
+    }
+
+    fn listing(code: &[Instruction]) -> String {
+        format!("{code:?}")
+    }
+
+    #[test]
+    fn jumps_are_relocated_across_removed_code() {
+        let mut module = module(vec![
+            Jmp { addr: 3 },         // 0: forward to 4, over 1 and 2
+            LoadNil,                 // 1: removed
+            Pop,                     // 2: removed
+            LoadTrue,                // 3
+            Jmp { addr: 0 },         // 4: to 5, which is removed
+            LoadNil,                 // 5: removed
+            JmpIfFalse { addr: -7 }, // 6: backward to 0, over 1, 2 and 5
+            Halt,                    // 7
+        ]);
+        let removed = [false, true, true, false, false, true, false, false];
+
+        assert!(remove(&mut module, &removed));
+        assert_eq!(
+            listing(&module.code),
+            listing(&[
+                Jmp { addr: 1 },
+                LoadTrue,
+                Jmp { addr: 0 },
+                JmpIfFalse { addr: -4 },
+                Halt,
+            ])
+        );
+        assert_eq!(module.lines, [0, 3, 4, 6, 7]);
+    }
+
+    #[test]
+    fn jump_targets_are_not_fused_away() {
+        // The loop jumps back to the IndexGet, so it has to stay on its own.
+        let code = vec![
+            Load { index: 0 },
+            IndexGet { index: 0 },
+            Call { args: 0 },
+            Pop,
+            Jmp { addr: -4 },
+            Halt,
+        ];
+        let mut targeted = module(code.clone());
+        fuse(&mut targeted);
+        assert_eq!(
+            listing(&targeted.code),
+            listing(&[
+                Load { index: 0 },
+                IndexGet { index: 0 },
+                CallPop { args: 0 },
+                Jmp { addr: -3 },
+                Halt,
+            ])
+        );
+
+        // Same again with the Pop as the target.
+        let mut code = code;
+        code[4] = Jmp { addr: -2 };
+        let mut targeted = module(code);
+        fuse(&mut targeted);
+        assert_eq!(
+            listing(&targeted.code),
+            listing(&[
+                LoadGlobalField {
+                    global: 0,
+                    field: 0
+                },
+                Call { args: 0 },
+                Pop,
+                Jmp { addr: -2 },
+                Halt,
+            ])
+        );
+    }
+
+    /// Run a module to the end, and describe every global it uses.
+    fn run(module: &Module) -> Vec<(String, String)> {
+        let mut runtime = Runtime::new(HeapConfig::default());
+        crate::register_builtins(&mut runtime);
+
+        let program = runtime.load(module);
+        let mut vm = runtime.spawn_vm(&program);
+        assert!(matches!(vm.run(u64::MAX), StopReason::Halt));
+
+        runtime.describe_globals(&module.globals)
+    }
+
+    #[test]
+    fn bench_scripts_give_the_same_results_optimized() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/bench");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let src = std::fs::read_to_string(&path).unwrap();
+            let program = parser::parse(&src, &LexerConfig::default()).unwrap();
+
+            let unoptimized = compiler::compile(&program).unwrap();
+            let mut optimized = compiler::compile(&program).unwrap();
+            optimize(&mut optimized);
+            assert!(optimized.code.len() < unoptimized.code.len());
+
+            assert_eq!(run(&optimized), run(&unoptimized), "{}", path.display());
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Turns source into the typed [AST](crate::ast) the compiler works on.
+//!
+//! The grammar lives in [`syntax`](crate::syntax) alone: the source is parsed into a lossless
+//! syntax tree first, and that tree is lowered here. Everything the syntax tree accepts
+//! lowers, so there's no second place for the two to disagree on what's valid.
+
+use crate::{
+    Error,
+    ast::{Branch, Expr, Span, Stmt},
+    lexer::{LexerConfig, TokenKind},
+    syntax::{self, NodeKind, SyntaxNode},
+};
+
+pub fn parse(src: &str, config: &LexerConfig) -> Result<Vec<Stmt>, Error> {
+    let tree = syntax::parse(src, config)?;
+    Ok(lower_block(&tree.root))
+}
+
+/// Lower the statements of a `Root` or `Block` node. Lone `;`s are dropped.
+fn lower_block(node: &SyntaxNode) -> Vec<Stmt> {
+    node.nodes().filter_map(lower_statement).collect()
+}
+
+fn lower_statement(node: &SyntaxNode) -> Option<Stmt> {
+    let span = Span::from(node.first_token());
+    let children: Vec<_> = node.nodes().collect();
+
+    let stmt = match node.kind {
+        NodeKind::EmptyStmt => return None,
+        NodeKind::ExprStmt => Stmt::Expr {
+            expr: lower_expr(children[0]),
+            span,
+        },
+        NodeKind::WhileStmt => Stmt::While {
+            cond: lower_expr(children[0]),
+            body: lower_block(children[1]),
+            span,
+        },
+        NodeKind::IfStmt => {
+            let mut branches = vec![Branch {
+                cond: lower_expr(children[0]),
+                body: lower_block(children[1]),
+                span,
+            }];
+            let mut else_body = None;
+
+            for clause in children[2..].iter() {
+                let parts: Vec<_> = clause.nodes().collect();
+                match clause.kind {
+                    NodeKind::ElseIfClause => branches.push(Branch {
+                        cond: lower_expr(parts[0]),
+                        body: lower_block(parts[1]),
+                        span: Span::from(clause.first_token()),
+                    }),
+                    NodeKind::ElseClause => else_body = Some(lower_block(parts[0])),
+                    kind => unreachable!("bug: {kind:?} in an IF statement"),
+                }
+            }
+
+            Stmt::If {
+                branches,
+                else_body,
+                span,
+            }
+        }
+        kind => unreachable!("bug: {kind:?} is not a statement"),
+    };
+
+    Some(stmt)
+}
+
+fn lower_expr(node: &SyntaxNode) -> Expr {
+    let token = node.first_token();
+    let span = Span::from(token);
+    let children: Vec<_> = node.nodes().collect();
+
+    match node.kind {
+        NodeKind::Literal => match token.kind {
+            TokenKind::Nil => Expr::Nil(span),
+            TokenKind::True => Expr::Bool(true, span),
+            TokenKind::False => Expr::Bool(false, span),
+            TokenKind::Number => {
+                let num = token.data.parse::<f64>().expect("bug: bad float");
+                Expr::Number(num, span)
+            }
+            // Strip the quotes. The syntax tree rejects unterminated strings.
+            TokenKind::String => {
+                Expr::String(token.data[1..token.data.len() - 1].to_string(), span)
+            }
+            kind => unreachable!("bug: {kind:?} is not a literal"),
+        },
+        NodeKind::Name => Expr::Var {
+            name: token.data.clone(),
+            span,
+        },
+        NodeKind::Alloc => Expr::Alloc(span),
+        NodeKind::Assign => Expr::Assign {
+            name: token.data.clone(),
+            value: Box::new(lower_expr(children[1])),
+            span,
+        },
+        NodeKind::Call => Expr::Call {
+            callee: Box::new(Expr::Var {
+                name: token.data.clone(),
+                span,
+            }),
+            args: lower_args(children[1]),
+            span,
+        },
+        NodeKind::Field | NodeKind::FieldAssign | NodeKind::MethodCall => {
+            let name = node
+                .token(TokenKind::Ident)
+                .expect("bug: field without a name");
+            let object = Box::new(lower_expr(children[0]));
+            let span = Span::from(name);
+            let name = name.data.clone();
+
+            match node.kind {
+                NodeKind::Field => Expr::Field { object, name, span },
+                NodeKind::FieldAssign => Expr::FieldAssign {
+                    object,
+                    name,
+                    value: Box::new(lower_expr(children[1])),
+                    span,
+                },
+                _ => Expr::Invoke {
+                    object,
+                    name,
+                    args: lower_args(children[1]),
+                    span,
+                },
+            }
+        }
+        kind => unreachable!("bug: {kind:?} is not an expression"),
+    }
+}
+
+/// Lower the arguments of an `ArgList` node.
+fn lower_args(node: &SyntaxNode) -> Vec<Expr> {
+    node.nodes().map(lower_expr).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_both(src: &str) -> (Result<String, Error>, Result<Vec<Stmt>, Error>) {
+        let config = LexerConfig::default();
+        let tree = syntax::parse(src, &config).map(|tree| tree.to_string());
+        (tree, parse(src, &config))
+    }
+
+    #[test]
+    fn keywords_as_names_are_rejected() {
+        for src in [
+            "IF = 1;",
+            "END = 1;",
+            "nil = 2;",
+            "WHILE x DO END = 1; END",
+            "x.END = 1;",
+            "print(THEN);",
+            "x = DO.y;",
+        ] {
+            let (tree, program) = parse_both(src);
+            assert!(
+                matches!(tree, Err(Error::KeywordAsIdentifier(_))),
+                "{src}: {tree:?}"
+            );
+            assert!(
+                matches!(program, Err(Error::KeywordAsIdentifier(_))),
+                "{src}: {program:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn syntax_errors_are_rejected() {
+        for src in [
+            "END",
+            "ELSE x;",
+            "x = \"abc;",
+            "x.;",
+            "x.1;",
+            "x",
+            "THEN",
+            "f(1 2);",
+            "- 1;",
+        ] {
+            let (tree, program) = parse_both(src);
+            assert!(tree.is_err(), "{src}: {tree:?}");
+            assert!(program.is_err(), "{src}: {program:?}");
+        }
+    }
+
+    #[test]
+    fn valid_programs_lower() {
+        let src = "
+            ♥ comment
+            x = alloc; x.y = \"s\";;
+            IF x.y THEN print(x.y, 1,); ELSEIF nil THEN ELSE x.f(true); END
+            WHILE false DO x = x.next; END
+        ";
+        let (tree, program) = parse_both(src);
+        assert_eq!(tree.unwrap(), src);
+
+        let program = program.unwrap();
+        assert_eq!(program.len(), 4);
+        let Stmt::If {
+            branches,
+            else_body,
+            span,
+        } = &program[2]
+        else {
+            panic!("expected an IF, got {:?}", program[2]);
+        };
+        assert_eq!(branches.len(), 2);
+        assert_eq!(else_body.as_ref().map(Vec::len), Some(1));
+        assert_eq!((span.line, span.col), (4, 12));
+    }
+}
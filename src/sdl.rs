@@ -21,10 +21,10 @@ pub fn register_sdl_functions(runtime: &mut Runtime) {
 
     runtime.register_function("init_video", 1, |args| {
         let sdl = match args.stack.pop().unwrap() {
-            Value::ExternObject(addr) => {
-                let obj = args.heap.get_extern(addr).unwrap();
-                obj.try_borrow::<sdl2::Sdl>().unwrap()
-            }
+            Value::ExternObject(addr) => match args.heap.get_extern(addr) {
+                Some(obj) => obj.try_borrow::<sdl2::Sdl>().unwrap(),
+                None => return args.segfault(addr),
+            },
             _ => todo!("expected external object"),
         };
 
@@ -47,10 +47,10 @@ pub fn register_sdl_functions(runtime: &mut Runtime) {
         };
 
         let video = match args.stack.pop().unwrap() {
-            Value::ExternObject(addr) => {
-                let obj = args.heap.get_extern(addr).unwrap();
-                obj.try_borrow::<sdl2::VideoSubsystem>().unwrap()
-            }
+            Value::ExternObject(addr) => match args.heap.get_extern(addr) {
+                Some(obj) => obj.try_borrow::<sdl2::VideoSubsystem>().unwrap(),
+                None => return args.segfault(addr),
+            },
             _ => todo!("expected external object"),
         };
 
@@ -71,7 +71,10 @@ pub fn register_sdl_functions(runtime: &mut Runtime) {
 
     runtime.register_function("into_canvas", 1, |args| {
         let obj = match args.stack.pop().unwrap() {
-            Value::ExternObject(addr) => args.heap.take_extern(addr),
+            Value::ExternObject(addr) => match args.heap.take_extern(addr) {
+                Some(obj) => obj,
+                None => return args.segfault(addr),
+            },
             _ => todo!("expected external object"),
         };
 
@@ -84,10 +87,10 @@ pub fn register_sdl_functions(runtime: &mut Runtime) {
 
     runtime.register_function("create_event_pump", 1, |args| {
         let sdl = match args.stack.pop().unwrap() {
-            Value::ExternObject(addr) => {
-                let obj = args.heap.get_extern(addr).unwrap();
-                obj.try_borrow::<sdl2::Sdl>().unwrap()
-            }
+            Value::ExternObject(addr) => match args.heap.get_extern(addr) {
+                Some(obj) => obj.try_borrow::<sdl2::Sdl>().unwrap(),
+                None => return args.segfault(addr),
+            },
             _ => todo!("expected external object"),
         };
 
@@ -102,10 +105,10 @@ pub fn register_sdl_functions(runtime: &mut Runtime) {
 
         let value = args.stack.pop().unwrap();
         let event_pump = match value {
-            Value::ExternObject(addr) => {
-                let obj = args.heap.get_extern_mut(addr).unwrap();
-                obj.try_borrow_mut::<sdl2::EventPump>().unwrap()
-            }
+            Value::ExternObject(addr) => match args.heap.get_extern_mut(addr) {
+                Some(obj) => obj.try_borrow_mut::<sdl2::EventPump>().unwrap(),
+                None => return args.segfault(addr),
+            },
             _ => todo!("expected external object"),
         };
 
@@ -130,9 +133,12 @@ pub fn register_sdl_functions(runtime: &mut Runtime) {
                 let keycode_id = args.field_id("keycode");
                 let keycode_value = args.strings.intern(keycode.unwrap().to_string());
 
-                let object = args.heap.get_mut(object_addr).unwrap();
-                object.data.insert(kind_id, Value::String(kind_value));
-                object.data.insert(keycode_id, Value::String(keycode_value));
+                let object = args
+                    .heap
+                    .get_mut(object_addr)
+                    .expect("bug: object was just allocated");
+                object.set(kind_id, Value::String(kind_value));
+                object.set(keycode_id, Value::String(keycode_value));
 
                 return Value::Object(object_addr);
             }
@@ -148,11 +154,12 @@ pub fn register_sdl_functions(runtime: &mut Runtime) {
         let g = args.stack.pop().unwrap().as_number() as u8;
         let r = args.stack.pop().unwrap().as_number() as u8;
         let canvas = match args.stack.pop().unwrap() {
-            Value::ExternObject(addr) => {
-                let obj = args.heap.get_extern_mut(addr).unwrap();
-                obj.try_borrow_mut::<sdl2::render::Canvas<sdl2::video::Window>>()
-                    .unwrap()
-            }
+            Value::ExternObject(addr) => match args.heap.get_extern_mut(addr) {
+                Some(obj) => obj
+                    .try_borrow_mut::<sdl2::render::Canvas<sdl2::video::Window>>()
+                    .unwrap(),
+                None => return args.segfault(addr),
+            },
             _ => todo!("expected external object"),
         };
 
@@ -167,11 +174,12 @@ pub fn register_sdl_functions(runtime: &mut Runtime) {
         let y = args.stack.pop().unwrap().as_number() as f32;
         let x = args.stack.pop().unwrap().as_number() as f32;
         let canvas = match args.stack.pop().unwrap() {
-            Value::ExternObject(addr) => {
-                let obj = args.heap.get_extern_mut(addr).unwrap();
-                obj.try_borrow_mut::<sdl2::render::Canvas<sdl2::video::Window>>()
-                    .unwrap()
-            }
+            Value::ExternObject(addr) => match args.heap.get_extern_mut(addr) {
+                Some(obj) => obj
+                    .try_borrow_mut::<sdl2::render::Canvas<sdl2::video::Window>>()
+                    .unwrap(),
+                None => return args.segfault(addr),
+            },
             _ => todo!("expected external object"),
         };
 
@@ -186,11 +194,12 @@ pub fn register_sdl_functions(runtime: &mut Runtime) {
         let y = args.stack.pop().unwrap().as_number() as f32;
         let x = args.stack.pop().unwrap().as_number() as f32;
         let canvas = match args.stack.pop().unwrap() {
-            Value::ExternObject(addr) => {
-                let obj = args.heap.get_extern_mut(addr).unwrap();
-                obj.try_borrow_mut::<sdl2::render::Canvas<sdl2::video::Window>>()
-                    .unwrap()
-            }
+            Value::ExternObject(addr) => match args.heap.get_extern_mut(addr) {
+                Some(obj) => obj
+                    .try_borrow_mut::<sdl2::render::Canvas<sdl2::video::Window>>()
+                    .unwrap(),
+                None => return args.segfault(addr),
+            },
             _ => todo!("expected external object"),
         };
 
@@ -201,11 +210,12 @@ pub fn register_sdl_functions(runtime: &mut Runtime) {
 
     runtime.register_function("clear", 1, |args| {
         let canvas = match args.stack.pop().unwrap() {
-            Value::ExternObject(addr) => {
-                let obj = args.heap.get_extern_mut(addr).unwrap();
-                obj.try_borrow_mut::<sdl2::render::Canvas<sdl2::video::Window>>()
-                    .unwrap()
-            }
+            Value::ExternObject(addr) => match args.heap.get_extern_mut(addr) {
+                Some(obj) => obj
+                    .try_borrow_mut::<sdl2::render::Canvas<sdl2::video::Window>>()
+                    .unwrap(),
+                None => return args.segfault(addr),
+            },
             _ => todo!("expected external object"),
         };
 
@@ -216,11 +226,12 @@ pub fn register_sdl_functions(runtime: &mut Runtime) {
 
     runtime.register_function("present", 1, |args| {
         let canvas = match args.stack.pop().unwrap() {
-            Value::ExternObject(addr) => {
-                let obj = args.heap.get_extern_mut(addr).unwrap();
-                obj.try_borrow_mut::<sdl2::render::Canvas<sdl2::video::Window>>()
-                    .unwrap()
-            }
+            Value::ExternObject(addr) => match args.heap.get_extern_mut(addr) {
+                Some(obj) => obj
+                    .try_borrow_mut::<sdl2::render::Canvas<sdl2::video::Window>>()
+                    .unwrap(),
+                None => return args.segfault(addr),
+            },
             _ => todo!("expected external object"),
         };
 
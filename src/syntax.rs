@@ -0,0 +1,443 @@
+//! Lossless concrete syntax tree.
+//!
+//! The compiler only cares about what a program means, so its [AST](crate::ast) has no room
+//! for whitespace and comments. Tooling (formatters, refactoring, editor features)
+//! needs to know how the program was _written_. The tree built here keeps every token along
+//! with the trivia in front of it, so printing it gives back the source byte-for-byte.
+//!
+//! This is also the only place the grammar is written down. The compiler's AST is lowered
+//! from this tree by the [parser](crate::parser).
+
+use std::fmt::{self, Write};
+
+use crate::{
+    Error,
+    lexer::{self, LexerConfig, Token, TokenKind},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeKind {
+    Root,
+    Block,
+
+    /// A lone `;`.
+    EmptyStmt,
+    ExprStmt,
+    IfStmt,
+    ElseIfClause,
+    ElseClause,
+    WhileStmt,
+
+    Literal,
+    Name,
+    Alloc,
+    Assign,
+    Call,
+    ArgList,
+    Field,
+    FieldAssign,
+    MethodCall,
+}
+
+/// A token together with the whitespace and comments directly in front of it.
+#[derive(Debug, Clone)]
+pub struct SyntaxToken {
+    pub leading: Vec<Token>,
+    pub token: Token,
+}
+
+#[derive(Debug, Clone)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+#[derive(Debug, Clone)]
+pub struct SyntaxNode {
+    pub kind: NodeKind,
+    pub children: Vec<SyntaxElement>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SyntaxTree {
+    pub root: SyntaxNode,
+    /// Trivia after the last token in the file.
+    pub trailing: Vec<Token>,
+}
+
+impl SyntaxNode {
+    /// The child nodes, skipping over the tokens.
+    pub fn nodes(&self) -> impl Iterator<Item = &SyntaxNode> {
+        self.children.iter().filter_map(|child| match child {
+            SyntaxElement::Node(node) => Some(node),
+            SyntaxElement::Token(_) => None,
+        })
+    }
+
+    /// The first child token of the given kind. Tokens inside child nodes aren't searched.
+    pub fn token(&self, kind: TokenKind) -> Option<&Token> {
+        self.children.iter().find_map(|child| match child {
+            SyntaxElement::Token(token) if token.token.kind == kind => Some(&token.token),
+            _ => None,
+        })
+    }
+
+    /// The first token of the node, wherever it's nested.
+    pub fn first_token(&self) -> &Token {
+        match self.children.first().expect("bug: empty syntax node") {
+            SyntaxElement::Node(node) => node.first_token(),
+            SyntaxElement::Token(token) => &token.token,
+        }
+    }
+}
+
+impl SyntaxTree {
+    /// Render the shape of the tree, one node or token per line.
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        dump_node(&mut out, &self.root, 0);
+        out
+    }
+}
+
+fn dump_node(out: &mut String, node: &SyntaxNode, depth: usize) {
+    let _ = writeln!(out, "{:indent$}{:?}", "", node.kind, indent = depth * 2);
+    for child in node.children.iter() {
+        match child {
+            SyntaxElement::Node(node) => dump_node(out, node, depth + 1),
+            SyntaxElement::Token(token) => {
+                let _ = writeln!(
+                    out,
+                    "{:indent$}{:?} {:?}",
+                    "",
+                    token.token.kind,
+                    token.token.data,
+                    indent = (depth + 1) * 2
+                );
+            }
+        }
+    }
+}
+
+impl fmt::Display for SyntaxToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for trivia in self.leading.iter() {
+            f.write_str(&trivia.data)?;
+        }
+        f.write_str(&self.token.data)
+    }
+}
+
+impl fmt::Display for SyntaxNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for child in self.children.iter() {
+            match child {
+                SyntaxElement::Node(node) => write!(f, "{node}")?,
+                SyntaxElement::Token(token) => write!(f, "{token}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SyntaxTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.root)?;
+        for trivia in self.trailing.iter() {
+            f.write_str(&trivia.data)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<SyntaxNode> for SyntaxElement {
+    fn from(node: SyntaxNode) -> Self {
+        SyntaxElement::Node(node)
+    }
+}
+
+impl From<SyntaxToken> for SyntaxElement {
+    fn from(token: SyntaxToken) -> Self {
+        SyntaxElement::Token(token)
+    }
+}
+
+pub fn parse(src: &str, config: &LexerConfig) -> Result<SyntaxTree, Error> {
+    // Attach each run of trivia to the token that follows it.
+    let mut tokens = vec![];
+    let mut leading = vec![];
+    for token in lexer::lex_lossless(src, config)? {
+        if token.kind.is_trivia() {
+            leading.push(token);
+        } else {
+            tokens.push(SyntaxToken {
+                leading: std::mem::take(&mut leading),
+                token,
+            });
+        }
+    }
+
+    tokens.reverse();
+    let mut parser = Parser { tokens };
+
+    let mut children = vec![];
+    while parser.peek().is_some() {
+        if parser.at_block_end()? {
+            let token = parser.bump();
+            return Err(Error::UnexpectedToken(token.token));
+        }
+
+        children.push(parser.statement()?.into());
+    }
+
+    Ok(SyntaxTree {
+        root: node(NodeKind::Root, children),
+        trailing: leading,
+    })
+}
+
+fn node(kind: NodeKind, children: Vec<SyntaxElement>) -> SyntaxNode {
+    SyntaxNode { kind, children }
+}
+
+struct Parser {
+    /// The tokens left to parse, last one first, so taking the next token is a pop and
+    /// looking ahead is an index.
+    tokens: Vec<SyntaxToken>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<TokenKind> {
+        self.peek_nth(0)
+    }
+
+    fn peek_nth(&self, n: usize) -> Option<TokenKind> {
+        let index = self.tokens.len().checked_sub(n + 1)?;
+        Some(self.tokens[index].token.kind)
+    }
+
+    fn next(&mut self) -> Option<SyntaxToken> {
+        self.tokens.pop()
+    }
+
+    fn bump(&mut self) -> SyntaxToken {
+        self.next().expect("bug: bumped past the end of the file")
+    }
+
+    fn expect(&mut self, expected: TokenKind) -> Result<SyntaxElement, Error> {
+        match self.next() {
+            Some(token) if token.token.kind == expected => Ok(token.into()),
+            Some(token) => Err(Error::UnexpectedTokenExpected(token.token.kind, expected)),
+            None => Err(Error::UnexpectedEOFExpected(expected)),
+        }
+    }
+
+    /// Check for something like `END = 1;` at the start of a statement. Without this it
+    /// would get reported as an unexpected `=` somewhere in a half parsed `IF` or `WHILE`.
+    fn check_keyword_assignment(&mut self) -> Result<(), Error> {
+        if self.peek().is_some_and(TokenKind::is_keyword)
+            && self.peek_nth(1) == Some(TokenKind::Equal)
+        {
+            return Err(Error::KeywordAsIdentifier(self.bump().token));
+        }
+
+        Ok(())
+    }
+
+    /// Whether the next token is the `END`, `ELSE` or `ELSEIF` closing a block.
+    fn at_block_end(&mut self) -> Result<bool, Error> {
+        self.check_keyword_assignment()?;
+
+        Ok(matches!(
+            self.peek(),
+            Some(TokenKind::End | TokenKind::Else | TokenKind::ElseIf)
+        ))
+    }
+
+    fn statement(&mut self) -> Result<SyntaxNode, Error> {
+        self.check_keyword_assignment()?;
+
+        match self.peek() {
+            Some(TokenKind::Semicolon) => Ok(node(NodeKind::EmptyStmt, vec![self.bump().into()])),
+            Some(TokenKind::If) => self.if_stmt(),
+            Some(TokenKind::While) => self.while_stmt(),
+            Some(
+                TokenKind::Nil
+                | TokenKind::True
+                | TokenKind::False
+                | TokenKind::Number
+                | TokenKind::Ident
+                | TokenKind::String
+                | TokenKind::Alloc,
+            ) => {
+                let expr = self.expr()?;
+                let semicolon = self.expect(TokenKind::Semicolon)?;
+                Ok(node(NodeKind::ExprStmt, vec![expr.into(), semicolon]))
+            }
+            Some(_) => Err(Error::UnexpectedToken(self.bump().token)),
+            None => Err(Error::UnexpectedEOF),
+        }
+    }
+
+    /// Statements up to the `END`, `ELSE` or `ELSEIF` closing the block.
+    fn block(&mut self) -> Result<SyntaxNode, Error> {
+        let mut children = vec![];
+        while self.peek().is_some() {
+            if self.at_block_end()? {
+                break;
+            }
+            children.push(self.statement()?.into());
+        }
+
+        Ok(node(NodeKind::Block, children))
+    }
+
+    fn if_stmt(&mut self) -> Result<SyntaxNode, Error> {
+        let mut children = vec![
+            self.expect(TokenKind::If)?,
+            self.expr()?.into(),
+            self.expect(TokenKind::Then)?,
+            self.block()?.into(),
+        ];
+
+        while self.peek() == Some(TokenKind::ElseIf) {
+            let clause = vec![
+                self.bump().into(),
+                self.expr()?.into(),
+                self.expect(TokenKind::Then)?,
+                self.block()?.into(),
+            ];
+            children.push(node(NodeKind::ElseIfClause, clause).into());
+        }
+
+        if self.peek() == Some(TokenKind::Else) {
+            let clause = vec![self.bump().into(), self.block()?.into()];
+            children.push(node(NodeKind::ElseClause, clause).into());
+        }
+
+        children.push(self.expect(TokenKind::End)?);
+
+        Ok(node(NodeKind::IfStmt, children))
+    }
+
+    fn while_stmt(&mut self) -> Result<SyntaxNode, Error> {
+        let children = vec![
+            self.expect(TokenKind::While)?,
+            self.expr()?.into(),
+            self.expect(TokenKind::Do)?,
+            self.block()?.into(),
+            self.expect(TokenKind::End)?,
+        ];
+
+        Ok(node(NodeKind::WhileStmt, children))
+    }
+
+    fn expr(&mut self) -> Result<SyntaxNode, Error> {
+        let mut lhs = self.atom()?;
+
+        while self.peek() == Some(TokenKind::Dot) {
+            let dot = self.bump().into();
+            let name = match self.next() {
+                Some(token) if token.token.kind == TokenKind::Ident => token.into(),
+                Some(token) if token.token.kind.is_keyword() => {
+                    return Err(Error::KeywordAsIdentifier(token.token));
+                }
+                Some(token) => return Err(Error::UnexpectedToken(token.token)),
+                None => return Err(Error::UnexpectedEOF),
+            };
+
+            lhs = match self.peek() {
+                Some(TokenKind::Equal) => {
+                    let equal = self.bump().into();
+                    let value = self.expr()?.into();
+                    node(
+                        NodeKind::FieldAssign,
+                        vec![lhs.into(), dot, name, equal, value],
+                    )
+                }
+                Some(TokenKind::LParen) => {
+                    let args = self.arg_list()?.into();
+                    node(NodeKind::MethodCall, vec![lhs.into(), dot, name, args])
+                }
+                _ => node(NodeKind::Field, vec![lhs.into(), dot, name]),
+            };
+        }
+
+        Ok(lhs)
+    }
+
+    fn atom(&mut self) -> Result<SyntaxNode, Error> {
+        let Some(token) = self.next() else {
+            return Err(Error::UnexpectedEOF);
+        };
+
+        match token.token.kind {
+            TokenKind::Nil | TokenKind::True | TokenKind::False | TokenKind::Number => {
+                Ok(node(NodeKind::Literal, vec![token.into()]))
+            }
+            // Unterminated strings are missing the closing quote.
+            TokenKind::String if token.token.data[1..].strip_suffix('"').is_none() => {
+                Err(Error::UnexpectedToken(token.token))
+            }
+            TokenKind::String => Ok(node(NodeKind::Literal, vec![token.into()])),
+            TokenKind::Alloc => Ok(node(NodeKind::Alloc, vec![token.into()])),
+            TokenKind::Ident => {
+                let name = node(NodeKind::Name, vec![token.into()]);
+
+                match self.peek() {
+                    Some(TokenKind::Equal) => {
+                        let equal = self.bump().into();
+                        let value = self.expr()?.into();
+                        Ok(node(NodeKind::Assign, vec![name.into(), equal, value]))
+                    }
+                    Some(TokenKind::LParen) => {
+                        let args = self.arg_list()?.into();
+                        Ok(node(NodeKind::Call, vec![name.into(), args]))
+                    }
+                    _ => Ok(name),
+                }
+            }
+            // A keyword followed by something that could only come after a value (`END;`,
+            // `print(THEN)`, `DO.x`, ...) was most likely meant to be a name.
+            kind if kind.is_keyword()
+                && matches!(
+                    self.peek(),
+                    Some(
+                        TokenKind::Equal
+                            | TokenKind::LParen
+                            | TokenKind::RParen
+                            | TokenKind::Dot
+                            | TokenKind::Comma
+                            | TokenKind::Semicolon
+                    )
+                ) =>
+            {
+                Err(Error::KeywordAsIdentifier(token.token))
+            }
+            _ => Err(Error::UnexpectedToken(token.token)),
+        }
+    }
+
+    /// `(` followed by comma separated arguments (with an optional trailing comma) and `)`.
+    fn arg_list(&mut self) -> Result<SyntaxNode, Error> {
+        let mut children = vec![self.expect(TokenKind::LParen)?];
+
+        while let Some(kind) = self.peek() {
+            if kind == TokenKind::RParen {
+                break;
+            }
+
+            children.push(self.expr()?.into());
+
+            if self.peek() == Some(TokenKind::Comma) {
+                children.push(self.bump().into());
+            } else {
+                break;
+            }
+        }
+
+        children.push(self.expect(TokenKind::RParen)?);
+
+        Ok(node(NodeKind::ArgList, children))
+    }
+}
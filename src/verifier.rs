@@ -0,0 +1,304 @@
+//! Static checks on a [`Module`] before it's run.
+//!
+//! The VM trusts its bytecode: it indexes the code, constant pool and globals without bounds
+//! checks and pops the stack assuming something is there. That's fine for what the compiler
+//! emits, but modules can also come from `.nacb` files (or a compiler bug). [`verify`] makes
+//! sure none of that can go wrong at runtime:
+//!
+//! - the code ends in `Halt`, so execution can't run off the end,
+//! - every jump lands on an instruction,
+//! - every global, field and constant index is in range,
+//! - the stack never underflows, and it's the same height every time control reaches an
+//!   instruction, no matter which path it took to get there.
+
+use crate::{Error, compiler::Module, vm::Instruction};
+
+pub fn verify(module: &Module) -> Result<(), Error> {
+    let code = &module.code;
+
+    if !matches!(code.last(), Some(Instruction::Halt)) {
+        return Err(error(
+            code.len().saturating_sub(1),
+            "code does not end in Halt",
+        ));
+    }
+
+    if module.lines.len() != code.len() {
+        return Err(error(
+            0,
+            format!(
+                "line table has {} entries for {} instructions",
+                module.lines.len(),
+                code.len()
+            ),
+        ));
+    }
+
+    for (addr, inst) in code.iter().enumerate() {
+        check_operands(module, addr, inst)?;
+    }
+
+    // Walk every reachable path, recording the stack height on entry to each instruction.
+    // Each instruction is only queued when its height is first discovered, so this visits
+    // every instruction at most once.
+    let mut depths: Vec<Option<usize>> = vec![None; code.len()];
+    let mut worklist = vec![0];
+    depths[0] = Some(0);
+
+    while let Some(addr) = worklist.pop() {
+        let depth = depths[addr].expect("bug: queued an instruction without a stack depth");
+        let inst = code[addr];
+
+        let (pops, pushes) = stack_effect(inst);
+        if depth < pops {
+            return Err(error(
+                addr,
+                format!("{inst:?} needs {pops} values but the stack only has {depth}"),
+            ));
+        }
+        let depth = depth - pops + pushes;
+
+        let mut successors = [None, None];
+        match inst {
+            Instruction::Halt => {}
+            Instruction::Jmp { addr: offset } => successors[0] = Some(jump_target(addr, offset)),
+            Instruction::JmpIfFalse { addr: offset } => {
+                successors[0] = Some(addr as i64 + 1);
+                successors[1] = Some(jump_target(addr, offset));
+            }
+            _ => successors[0] = Some(addr as i64 + 1),
+        }
+
+        for target in successors.into_iter().flatten() {
+            if target < 0 || target >= code.len() as i64 {
+                return Err(error(
+                    addr,
+                    format!("control flow leaves the code (target {target})"),
+                ));
+            }
+
+            let target = target as usize;
+            match depths[target] {
+                None => {
+                    depths[target] = Some(depth);
+                    worklist.push(target);
+                }
+                Some(existing) if existing != depth => {
+                    return Err(error(
+                        target,
+                        format!(
+                            "stack depth mismatch: reached with {existing} values and with {depth}"
+                        ),
+                    ));
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Make sure every operand in the instruction points at something that exists.
+fn check_operands(module: &Module, addr: usize, inst: &Instruction) -> Result<(), Error> {
+    let check = |index: u32, len: usize, table: &str| {
+        if index as usize >= len {
+            return Err(error(
+                addr,
+                format!("{table} index {index} is out of range (only {len})"),
+            ));
+        }
+        Ok(())
+    };
+
+    let globals = module.globals.len();
+    let fields = module.fields.len();
+
+    match *inst {
+        Instruction::Load { index } | Instruction::Store { index } => {
+            check(index, globals, "global")
+        }
+        Instruction::IndexGet { index }
+        | Instruction::IndexSet { index }
+        | Instruction::IndexSetPop { index }
+        | Instruction::Invoke { sym: index, .. } => check(index, fields, "field"),
+        Instruction::LoadGlobalField { global, field } => {
+            check(global, globals, "global")?;
+            check(field, fields, "field")
+        }
+        Instruction::LoadConst { index } => check(index, module.constants.len(), "constant"),
+        _ => Ok(()),
+    }
+}
+
+/// How many values an instruction pops off the stack and how many it pushes back.
+fn stack_effect(inst: Instruction) -> (usize, usize) {
+    match inst {
+        Instruction::Load { .. }
+        | Instruction::LoadNil
+        | Instruction::LoadTrue
+        | Instruction::LoadFalse
+        | Instruction::LoadConst { .. }
+        | Instruction::Alloc
+        | Instruction::LoadGlobalField { .. } => (0, 1),
+        Instruction::Store { .. } | Instruction::Pop | Instruction::JmpIfFalse { .. } => (1, 0),
+        Instruction::IndexGet { .. } => (1, 1),
+        Instruction::IndexSet { .. } => (2, 1),
+        Instruction::IndexSetPop { .. } => (2, 0),
+        // The callee (or receiver) sits below the arguments.
+        Instruction::Call { args } | Instruction::Invoke { args, .. } => (args as usize + 1, 1),
+        Instruction::CallPop { args } => (args as usize + 1, 0),
+        Instruction::Jmp { .. } | Instruction::Halt => (0, 0),
+    }
+}
+
+/// Jumps are relative to the instruction following the jump.
+fn jump_target(addr: usize, offset: i32) -> i64 {
+    addr as i64 + 1 + offset as i64
+}
+
+fn error(addr: usize, reason: impl ToString) -> Error {
+    Error::VerifyFailed {
+        addr,
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler, compiler::Constant, lexer::LexerConfig, optimizer, parser};
+    use Instruction::*;
+
+    /// A module with one global, one field and one constant.
+    fn module(code: Vec<Instruction>) -> Module {
+        Module {
+            lines: vec![1; code.len()],
+            code,
+            constants: vec![Constant::Number(1.0)],
+            globals: vec!["x".to_string()],
+            fields: vec!["y".to_string()],
+        }
+    }
+
+    /// The address the verifier blamed.
+    fn rejects(module: &Module) -> usize {
+        match verify(module) {
+            Err(Error::VerifyFailed { addr, .. }) => addr,
+            result => panic!("expected a verify error, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn accepts_valid_code() {
+        let code = vec![
+            LoadConst { index: 0 },
+            JmpIfFalse { addr: 2 },
+            Load { index: 0 },
+            Jmp { addr: 1 },
+            LoadNil,
+            Store { index: 0 },
+            LoadGlobalField {
+                global: 0,
+                field: 0,
+            },
+            Pop,
+            Halt,
+        ];
+        verify(&module(code)).unwrap();
+    }
+
+    #[test]
+    fn code_must_end_in_halt() {
+        assert_eq!(rejects(&module(vec![])), 0);
+        assert_eq!(rejects(&module(vec![LoadNil, Pop])), 1);
+        // Halting somewhere else doesn't count.
+        assert_eq!(rejects(&module(vec![Halt, LoadNil, Pop])), 2);
+    }
+
+    #[test]
+    fn indices_must_be_in_range() {
+        for inst in [
+            Load { index: 1 },
+            LoadConst { index: 1 },
+            IndexGet { index: 1 },
+            LoadGlobalField {
+                global: 1,
+                field: 0,
+            },
+            LoadGlobalField {
+                global: 0,
+                field: 1,
+            },
+        ] {
+            let module = module(vec![Alloc, inst, Pop, Pop, Halt]);
+            assert_eq!(rejects(&module), 1, "{inst:?}");
+        }
+    }
+
+    #[test]
+    fn jumps_must_stay_in_the_code() {
+        // 1 jumps just past the Halt.
+        for offset in [-3, 1, i32::MAX, i32::MIN] {
+            let module = module(vec![LoadTrue, JmpIfFalse { addr: offset }, Halt]);
+            assert_eq!(rejects(&module), 1, "{offset}");
+        }
+
+        // Unconditional jumps too.
+        assert_eq!(rejects(&module(vec![Jmp { addr: 1 }, Halt])), 0);
+    }
+
+    #[test]
+    fn stack_depth_must_agree_at_merge_points() {
+        // The branch skips the LoadNil, so the Halt is reached with 0 and with 1 values.
+        let code = vec![LoadTrue, JmpIfFalse { addr: 1 }, LoadNil, Halt];
+        assert_eq!(rejects(&module(code)), 3);
+
+        // A loop that grows the stack every time around.
+        let code = vec![
+            LoadNil,
+            LoadTrue,
+            JmpIfFalse { addr: 1 },
+            Jmp { addr: -4 },
+            Halt,
+        ];
+        assert_eq!(rejects(&module(code)), 0);
+    }
+
+    #[test]
+    fn stack_must_not_underflow() {
+        assert_eq!(rejects(&module(vec![Pop, Halt])), 0);
+        assert_eq!(
+            rejects(&module(vec![Alloc, IndexSet { index: 0 }, Halt])),
+            1
+        );
+        assert_eq!(rejects(&module(vec![LoadNil, Call { args: 1 }, Halt])), 1);
+    }
+
+    #[test]
+    fn line_table_must_match_the_code() {
+        for lines in [vec![], vec![1], vec![1, 1, 1]] {
+            let mut module = module(vec![LoadNil, Halt]);
+            module.lines = lines;
+            assert_eq!(rejects(&module), 0);
+        }
+    }
+
+    #[test]
+    fn bench_scripts_pass() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/bench");
+        let mut scripts = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let src = std::fs::read_to_string(&path).unwrap();
+            let program = parser::parse(&src, &LexerConfig::default()).unwrap();
+
+            let mut module = compiler::compile(&program).unwrap();
+            verify(&module).unwrap_or_else(|err| panic!("{}: {err:?}", path.display()));
+            optimizer::optimize(&mut module);
+            verify(&module).unwrap_or_else(|err| panic!("{}: {err:?}", path.display()));
+            scripts += 1;
+        }
+        assert!(scripts > 0);
+    }
+}
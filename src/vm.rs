@@ -1,12 +1,52 @@
 use std::{
     any::TypeId,
-    collections::{HashMap, hash_map::Iter},
+    collections::{HashMap, VecDeque, hash_map::Iter},
     marker::PhantomData,
+    mem::ManuallyDrop,
     ptr::NonNull,
     vec,
 };
 
-use crate::{compiler::Module, gc::GcMetrics};
+use crate::{
+    compiler::{Constant, Module},
+    gc::{self, CycleScore, GcMetrics, GcPolicy, KeepEverything, TracingGc},
+};
+
+pub mod packed;
+pub mod regvm;
+
+pub use packed::PackedValue;
+
+/// How values are stored on the [`Stack`] and in [`Object`] fields. With the `nan-boxing`
+/// feature that's an 8-byte [`PackedValue`], otherwise the 16-byte [`Value`] enum itself.
+#[cfg(feature = "nan-boxing")]
+pub type Slot = PackedValue;
+#[cfg(not(feature = "nan-boxing"))]
+pub type Slot = Value;
+
+#[cfg(feature = "nan-boxing")]
+#[inline]
+pub fn to_slot(value: Value) -> Slot {
+    PackedValue::pack(value)
+}
+
+#[cfg(feature = "nan-boxing")]
+#[inline]
+pub fn from_slot(slot: Slot) -> Value {
+    slot.unpack()
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+#[inline]
+pub fn to_slot(value: Value) -> Slot {
+    value
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+#[inline]
+pub fn from_slot(slot: Slot) -> Value {
+    slot
+}
 
 #[derive(Debug, Clone, Copy)]
 pub enum Instruction {
@@ -22,6 +62,8 @@ pub enum Instruction {
     IndexGet {
         index: u32,
     },
+    // Pop a value and an object, set the object's field to the value and push the value
+    // back, since a field assignment is an expression like any other.
     IndexSet {
         index: u32,
     },
@@ -32,14 +74,10 @@ pub enum Instruction {
     LoadTrue,
     // Push `false` to the top of the stack.
     LoadFalse,
-    // Push a constant unto the top of the stack.
+    // Push a constant from the module's constant pool unto the top of the stack.
     LoadConst {
         index: u32,
     },
-    // Push a string to the top of the stack.
-    LoadString {
-        index: u32,
-    },
 
     // Allocate a new object and push it to the top of the stack.
     Alloc,
@@ -66,6 +104,23 @@ pub enum Instruction {
     Pop,
     // Halt execution.
     Halt,
+
+    // Superinstructions. These are never emitted by the compiler directly, the optimizer
+    // fuses common instruction pairs into them.
+
+    // `Load` + `IndexGet`: read a field of the object in a global variable.
+    LoadGlobalField {
+        global: u32,
+        field: u32,
+    },
+    // `IndexSet` + `Pop`: set a field without pushing the new value.
+    IndexSetPop {
+        index: u32,
+    },
+    // `Call` + `Pop`: call a function and discard the result.
+    CallPop {
+        args: u8,
+    },
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
@@ -75,8 +130,19 @@ pub enum Value {
     Number(f64),
     String(u32),
     FunctionPtr(u32),
-    Object(u32),
-    ExternObject(u32),
+    Object(Handle),
+    ExternObject(Handle),
+}
+
+/// Points at a cell in the [`Heap`]. A cell's generation goes up every time it's freed, so
+/// a handle to a freed object can be told apart from one to whatever lives there now.
+///
+/// Generations wrap around after 65536 frees of the same cell, at which point a handle that
+/// old looks live again. That's enough to catch use-after-free bugs, not to rule them out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Handle {
+    pub addr: u32,
+    pub generation: u16,
 }
 
 impl Value {
@@ -87,29 +153,22 @@ impl Value {
         }
     }
 
-    pub fn try_as_extern(&self) -> Option<u32> {
+    pub fn try_as_extern(&self) -> Option<Handle> {
         match self {
-            Value::ExternObject(addr) => Some(*addr),
+            Value::ExternObject(handle) => Some(*handle),
             _ => None,
         }
     }
 
+    /// The NaN-boxed bits of this value (see [`PackedValue`]).
     pub fn to_u64(&self) -> u64 {
-        match self {
-            Value::Nil => 0,
-            Value::Bool(bool) => *bool as u64,
-            Value::Number(num) => num.to_bits(),
-            Value::String(addr) => *addr as u64,
-            Value::FunctionPtr(addr) => *addr as u64,
-            Value::Object(addr) => *addr as u64,
-            Value::ExternObject(addr) => *addr as u64,
-        }
+        PackedValue::pack(*self).to_bits()
     }
 }
 
 #[derive(Debug)]
 pub struct Object {
-    pub data: ahash::HashMap<u32, Value>,
+    pub data: ahash::HashMap<u32, Slot>,
 }
 
 impl Object {
@@ -118,12 +177,70 @@ impl Object {
             data: ahash::HashMap::default(),
         }
     }
+
+    /// The value of a field, or `nil` if it was never set.
+    pub fn get(&self, field: u32) -> Value {
+        self.data
+            .get(&field)
+            .map(|slot| from_slot(*slot))
+            .unwrap_or(Value::Nil)
+    }
+
+    pub fn set(&mut self, field: u32, value: Value) {
+        self.data.insert(field, to_slot(value));
+    }
 }
 
+/// The VM's value stack, which native functions pop their arguments off. Values are stored
+/// as [`Slot`]s and converted on the way in and out.
+#[derive(Debug, Default)]
+pub struct Stack {
+    slots: Vec<Slot>,
+}
+
+impl Stack {
+    pub fn push(&mut self, value: Value) {
+        self.slots.push(to_slot(value));
+    }
+
+    pub fn pop(&mut self) -> Option<Value> {
+        self.slots.pop().map(from_slot)
+    }
+
+    pub fn get(&self, index: usize) -> Value {
+        from_slot(self.slots[index])
+    }
+
+    pub fn set(&mut self, index: usize, value: Value) {
+        self.slots[index] = to_slot(value);
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.slots.truncate(len);
+    }
+
+    pub fn resize(&mut self, len: usize, value: Value) {
+        self.slots.resize(len, to_slot(value));
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Value> + '_ {
+        self.slots.iter().map(|slot| from_slot(*slot))
+    }
+}
+
+/// A Rust value owned by the heap. It's dropped when the object is freed, unless it was
+/// moved out first with [`ExternObject::into_obj`].
 #[derive(Debug)]
 pub struct ExternObject {
     type_id: TypeId,
-    #[allow(unused)]
     drop: unsafe fn(NonNull<()>),
     value: NonNull<()>,
 }
@@ -172,31 +289,36 @@ impl ExternObject {
         }
     }
 
-    pub fn into_obj<T: 'static>(self) -> Option<Box<T>> {
+    /// Move the value out. If it isn't a `T`, the object is handed back untouched.
+    pub fn into_obj<T: 'static>(self) -> Result<Box<T>, Self> {
         if self.is::<T>() {
-            Some(unsafe { Box::from_raw(self.value.cast::<T>().as_ptr()) })
+            // The box owns the value now, so our destructor mustn't run.
+            let this = ManuallyDrop::new(self);
+            Ok(unsafe { Box::from_raw(this.value.cast::<T>().as_ptr()) })
         } else {
-            None
+            Err(self)
         }
     }
 }
 
 impl Drop for ExternObject {
     fn drop(&mut self) {
-        // let value = self.value;
-        // Safety: this value is being dropped.
-        // unsafe { (self.drop)(value) };
+        // Safety: `value` was boxed by `new` for the type `drop` was made for, and `into_obj`
+        // skips this when it takes the box, so it's only ever freed once.
+        unsafe { (self.drop)(self.value) };
     }
 }
 
 pub struct FunctionArgs<'r> {
-    pub stack: &'r mut Vec<Value>,
+    pub stack: &'r mut Stack,
     pub heap: &'r mut Heap,
     pub strings: &'r mut Interner,
     pub field_to_id_map: &'r mut ahash::HashMap<String, u32>,
     /// Request a GC cycle. Note that if this is set, the stack must be
     /// restored to its pre-call state (or bad things will happen).
     pub needs_gc: &'r mut bool,
+    /// Fail the call. The VM stops with this error and ignores the return value.
+    pub error: &'r mut Option<RuntimeError>,
 }
 
 impl<'r> FunctionArgs<'r> {
@@ -210,6 +332,13 @@ impl<'r> FunctionArgs<'r> {
             }
         }
     }
+
+    /// Fail the call with a segmentation fault for using `handle` after its object was
+    /// freed. Returns a value to return from the function with.
+    pub fn segfault(self, handle: Handle) -> Value {
+        *self.error = Some(self.heap.segfault(handle));
+        Value::Nil
+    }
 }
 
 pub struct Runtime {
@@ -217,11 +346,14 @@ pub struct Runtime {
     global_name_map: HashMap<String, usize>,
     field_to_id_map: ahash::HashMap<String, u32>,
     functions: Vec<FunctionDef>,
-    stack: Vec<Value>,
+    stack: Stack,
     pub ip: usize,
     pub heap: Heap,
     pub interner: Interner,
     pub gc_metrics: GcMetrics,
+    /// Decides what survives a GC cycle, see [`Runtime::collect_garbage`]. Defaults to
+    /// [`TracingGc`].
+    pub gc: Box<dyn GcPolicy>,
 }
 
 #[derive(Default)]
@@ -252,8 +384,78 @@ pub struct FunctionDef {
 }
 
 impl Runtime {
-    pub fn spawn_vm<'r>(&'r mut self, module: &'r Module) -> Vm<'r> {
-        Vm { module, vm: self }
+    /// Link a module into this runtime. Globals and fields are looked up (or created) by
+    /// name, and strings in the constant pool are interned. The same module can be loaded
+    /// into any number of runtimes.
+    pub fn load(&mut self, module: &Module) -> Program {
+        let globals: Vec<u32> = module
+            .globals
+            .iter()
+            .map(|name| self.get_global_index(name) as u32)
+            .collect();
+
+        let fields: Vec<u32> = module
+            .fields
+            .iter()
+            .map(|name| self.get_field_index(name))
+            .collect();
+
+        let code = module
+            .code
+            .iter()
+            .map(|inst| match *inst {
+                Instruction::Load { index } => Instruction::Load {
+                    index: globals[index as usize],
+                },
+                Instruction::Store { index } => Instruction::Store {
+                    index: globals[index as usize],
+                },
+                Instruction::IndexGet { index } => Instruction::IndexGet {
+                    index: fields[index as usize],
+                },
+                Instruction::IndexSet { index } => Instruction::IndexSet {
+                    index: fields[index as usize],
+                },
+                Instruction::Invoke { args, sym } => Instruction::Invoke {
+                    args,
+                    sym: fields[sym as usize],
+                },
+                Instruction::LoadGlobalField { global, field } => Instruction::LoadGlobalField {
+                    global: globals[global as usize],
+                    field: fields[field as usize],
+                },
+                Instruction::IndexSetPop { index } => Instruction::IndexSetPop {
+                    index: fields[index as usize],
+                },
+                inst => inst,
+            })
+            .collect();
+
+        // Strings get interned up front so `LoadConst` can push them as plain
+        // `Value::String`s.
+        let constants = module
+            .constants
+            .iter()
+            .map(|constant| match constant {
+                Constant::Number(num) => Value::Number(*num),
+                Constant::String(string) => Value::String(self.interner.intern(string.clone())),
+            })
+            .collect();
+
+        Program {
+            code,
+            lines: module.lines.clone(),
+            constants,
+            breakpoints: Default::default(),
+        }
+    }
+
+    pub fn spawn_vm<'r>(&'r mut self, program: &'r Program) -> Vm<'r> {
+        Vm {
+            program,
+            vm: self,
+            steps: 0,
+        }
     }
 
     pub fn set_global(&mut self, name: impl ToString, value: Value) {
@@ -293,13 +495,13 @@ impl Runtime {
             Value::Number(num) => num.to_string(),
             Value::String(addr) => self.interner.get(addr).clone(),
             Value::FunctionPtr(addr) => format!("fn<{addr}>"),
-            Value::Object(idx) => match self.heap.get(idx) {
+            Value::Object(handle) => match self.heap.get(handle) {
                 Some(obj) => format!("{obj:?}"),
-                None => format!("Object {{ <oops.__{idx}> }}"),
+                None => format!("Object {{ <oops.__{}> }}", handle.addr),
             },
-            Value::ExternObject(addr) => match self.heap.get_extern(addr) {
+            Value::ExternObject(handle) => match self.heap.get_extern(handle) {
                 Some(obj) => format!("{obj:?}"),
-                None => format!("ExternObject {{ <oops.__{addr}> }}"),
+                None => format!("ExternObject {{ <oops.__{}> }}", handle.addr),
             },
         }
     }
@@ -315,6 +517,11 @@ impl Runtime {
         self.globals.iter().copied()
     }
 
+    /// The VM stack, bottom first.
+    pub fn stack_values(&self) -> impl Iterator<Item = Value> + '_ {
+        self.stack.iter()
+    }
+
     pub fn field_ids(&self) -> impl Iterator<Item = (&str, u32)> {
         self.field_to_id_map
             .iter()
@@ -353,20 +560,81 @@ impl Runtime {
         self.stack.clear();
         self.ip = 0;
     }
+
+    /// Mark every object that's reachable from the roots: the globals and the VM stack
+    /// (where call frames will live too). See [`Heap::trace`].
+    pub fn trace(&self) -> Vec<bool> {
+        self.heap
+            .trace(self.global_values().chain(self.stack_values()))
+    }
+
+    /// Run a GC cycle with this runtime's [`GcPolicy`], see [`gc::run_cycle`].
+    pub fn collect_garbage(&mut self) -> CycleScore {
+        // The policy gets the whole runtime to look at, so it's moved out for the cycle.
+        let mut policy = std::mem::replace(&mut self.gc, Box::new(KeepEverything));
+        let score = gc::run_cycle(policy.as_mut(), self);
+        self.gc = policy;
+        score
+    }
 }
 
 impl Runtime {
-    pub fn new() -> Self {
+    pub fn new(heap: HeapConfig) -> Self {
+        let mut field_to_id_map = ahash::HashMap::default();
+        field_to_id_map.insert("finalize".to_string(), FINALIZE_FIELD);
+
         Self {
             globals: vec![],
             global_name_map: Default::default(),
-            field_to_id_map: Default::default(),
+            field_to_id_map,
             interner: Default::default(),
             functions: vec![],
-            stack: vec![],
+            stack: Stack::default(),
             ip: 0,
-            heap: Heap::new(20),
+            heap: Heap::new(heap),
             gc_metrics: GcMetrics::default(),
+            gc: Box::new(TracingGc),
+        }
+    }
+}
+
+/// The id of the `finalize` field, which every runtime reserves.
+///
+/// Objects can ask to be told when the player collects them by putting a function in this
+/// field. Rather than being freed during the sweep, such an object is queued, and once the GC
+/// cycle is over [`Vm::run`] calls the function with the object (which can still be read
+/// and written as normal) before anything else. The object is freed as soon as the function
+/// returns.
+///
+/// Everything a queued object points to is kept alive until its finalizer has run, so the
+/// finalizer can follow its fields. If the finalizer runs out of memory, `run` stops with
+/// [`StopReason::RequestGC`] and calls it again after the GC cycle.
+///
+/// There's no resurrection: a finalizer may store the object somewhere, but it's freed all
+/// the same, and using it later is a segmentation fault like any other freed object. So each
+/// object is finalized at most once.
+///
+/// Finalizers are native functions taking one argument, e.g. `enemy.finalize = explode;`.
+pub const FINALIZE_FIELD: u32 = 0;
+
+/// How big the heap is and how it grows.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapConfig {
+    /// Number of objects the heap has room for to begin with.
+    pub size: usize,
+    /// Double the heap after a GC cycle that frees less than this fraction of it. `None`
+    /// keeps it at its initial size.
+    pub grow_below: Option<f64>,
+    /// The heap never grows past this many objects.
+    pub max_size: usize,
+}
+
+impl Default for HeapConfig {
+    fn default() -> Self {
+        Self {
+            size: 20,
+            grow_below: None,
+            max_size: usize::MAX,
         }
     }
 }
@@ -374,6 +642,16 @@ impl Runtime {
 pub struct Heap {
     next_free: usize,
     objects: Vec<HeapValue>,
+    /// Bookkeeping for each cell in `objects`.
+    cells: Vec<CellInfo>,
+    /// Number of GC cycles that have finished.
+    cycle: usize,
+    /// Collected objects waiting for their finalizer to run, and the finalizer.
+    finalizers: VecDeque<(Handle, u32)>,
+    grow_below: Option<f64>,
+    max_size: usize,
+    /// GC cycles since the last successful allocation.
+    cycles_since_alloc: usize,
 }
 
 pub enum HeapValue {
@@ -382,32 +660,104 @@ pub enum HeapValue {
     Extern(ExternObject),
 }
 
+#[derive(Debug, Clone, Copy, Default)]
+struct CellInfo {
+    generation: u16,
+    /// The GC cycle that last freed this cell, if it was freed by one.
+    freed_in: Option<usize>,
+    /// The object in this cell has been collected and is waiting for its finalizer.
+    finalizing: bool,
+}
+
 impl Heap {
-    pub fn new(size: usize) -> Self {
-        let mut objects = vec![];
+    pub fn new(config: HeapConfig) -> Self {
+        let mut heap = Self {
+            next_free: 0,
+            objects: vec![],
+            cells: vec![],
+            cycle: 0,
+            finalizers: VecDeque::new(),
+            grow_below: config.grow_below,
+            max_size: config.max_size,
+            cycles_since_alloc: 0,
+        };
+        heap.grow(config.size.min(config.max_size));
+        heap
+    }
 
-        for i in 0..size {
-            objects.push(HeapValue::Free { next: i + 1 });
+    /// Add free cells until the heap has room for `size` objects.
+    fn grow(&mut self, size: usize) {
+        // The free list ends with a `next` equal to the heap's size, which is exactly where
+        // the new cells start, so they're appended to the list for free.
+        for i in self.objects.len()..size {
+            self.objects.push(HeapValue::Free { next: i + 1 });
+            self.cells.push(CellInfo::default());
         }
+    }
 
-        Self {
-            next_free: 0,
-            objects,
-        }
+    /// Whether the heap is full even though a GC cycle has run since the last allocation,
+    /// so collecting again won't help.
+    pub fn is_exhausted(&self) -> bool {
+        self.cycles_since_alloc > 0 && self.next_free >= self.objects.len()
     }
 
+    /// Free every object that isn't `marked`, ending a GC cycle. Objects with a finalizer
+    /// are queued instead, along with everything they point to (see [`FINALIZE_FIELD`]). If
+    /// the growth policy says so, the heap grows afterwards. Returns how many objects were
+    /// freed, which doesn't include the queued ones.
     pub fn sweep(&mut self, marked: &[bool]) -> usize {
         assert!(marked.len() == self.objects.len());
         let mut free_count = 0;
+        self.cycle += 1;
 
-        marked
-            .iter()
-            .enumerate()
-            .filter_map(|(addr, marked)| (!marked).then_some(addr))
-            .for_each(|addr| {
-                free_count += 1;
-                self.free(addr as u32);
-            });
+        for (addr, marked) in marked.iter().enumerate() {
+            if *marked || self.cells[addr].finalizing {
+                continue;
+            }
+
+            if let HeapValue::Object(object) = &self.objects[addr]
+                && let Value::FunctionPtr(func) = object.get(FINALIZE_FIELD)
+            {
+                self.finalizers.push_back((self.handle(addr), func));
+                self.cells[addr].finalizing = true;
+                self.cells[addr].freed_in = Some(self.cycle);
+            }
+        }
+
+        // Finalizers can follow their object's fields, so whatever the queued objects point
+        // to has to outlive them. This includes objects queued by earlier cycles.
+        let kept = if self.finalizers.is_empty() {
+            vec![false; self.objects.len()]
+        } else {
+            self.trace(
+                self.finalizers
+                    .iter()
+                    .map(|(handle, _)| Value::Object(*handle)),
+            )
+        };
+
+        for (addr, (marked, kept)) in marked.iter().zip(kept).enumerate() {
+            // Cells that are already free stay on the free list as they are, and queued
+            // objects are freed once they've been finalized.
+            if *marked || kept || self.cells[addr].finalizing {
+                continue;
+            }
+            if let HeapValue::Free { .. } = self.objects[addr] {
+                continue;
+            }
+
+            self.release(addr, Some(self.cycle));
+            free_count += 1;
+        }
+
+        let size = self.objects.len();
+        if let Some(grow_below) = self.grow_below
+            && (free_count as f64) < grow_below * size as f64
+        {
+            self.grow(size.saturating_mul(2).min(self.max_size));
+        }
+
+        self.cycles_since_alloc += 1;
 
         free_count
     }
@@ -430,7 +780,7 @@ impl Heap {
 
     /// Allocate a new object, returning it's "address" in the heap. This virtual
     /// address can be used to retrieve the object.
-    pub fn alloc(&mut self) -> Option<u32> {
+    pub fn alloc(&mut self) -> Option<Handle> {
         if self.next_free < self.objects.len() {
             let index = self.next_free;
 
@@ -441,8 +791,9 @@ impl Heap {
 
             let obj = Object::new();
             self.objects[index] = HeapValue::Object(obj);
+            self.cycles_since_alloc = 0;
 
-            Some(index as u32)
+            Some(self.handle(index))
         } else {
             None
         }
@@ -450,7 +801,7 @@ impl Heap {
 
     /// Allocate a new object, returning it's "address" in the heap. This virtual
     /// address can be used to retrieve the object.
-    pub fn alloc_extern<T: 'static>(&mut self, value: T) -> Option<u32> {
+    pub fn alloc_extern<T: 'static>(&mut self, value: T) -> Option<Handle> {
         if self.next_free < self.objects.len() {
             let index = self.next_free;
 
@@ -461,63 +812,135 @@ impl Heap {
 
             let obj = ExternObject::new::<T>(value);
             self.objects[index] = HeapValue::Extern(obj);
+            self.cycles_since_alloc = 0;
 
-            Some(index as u32)
+            Some(self.handle(index))
         } else {
             None
         }
     }
 
-    pub fn take_extern(&mut self, addr: u32) -> ExternObject {
-        let addr = addr as usize;
+    fn handle(&self, addr: usize) -> Handle {
+        Handle {
+            addr: addr as u32,
+            generation: self.cells[addr].generation,
+        }
+    }
+
+    /// Whether `handle` still points at the object it was made for.
+    pub fn is_live(&self, handle: Handle) -> bool {
+        self.cells[handle.addr as usize].generation == handle.generation
+    }
 
-        if addr >= self.objects.len() {
-            panic!("bug: Invalid address");
+    /// A reference to the object at `addr`, or `None` if the cell is free.
+    pub fn value_at(&self, addr: usize) -> Option<Value> {
+        match self.objects[addr] {
+            HeapValue::Free { .. } => None,
+            HeapValue::Object(_) => Some(Value::Object(self.handle(addr))),
+            HeapValue::Extern(_) => Some(Value::ExternObject(self.handle(addr))),
         }
+    }
 
-        let prev_free = self.next_free;
-        let obj = std::mem::replace(&mut self.objects[addr], HeapValue::Free { next: prev_free });
-        self.next_free = addr;
+    /// Mark every object that's reachable from `roots`. Objects are followed through their
+    /// fields, while external objects are opaque and only marked themselves. Handles to freed
+    /// objects don't mark anything.
+    pub fn trace(&self, roots: impl IntoIterator<Item = Value>) -> Vec<bool> {
+        let mut marked = vec![false; self.size()];
+        let mut worklist: Vec<Value> = roots.into_iter().collect();
+
+        while let Some(value) = worklist.pop() {
+            let handle = match value {
+                Value::Object(handle) | Value::ExternObject(handle) => handle,
+                _ => continue,
+            };
+            if !self.is_live(handle) || marked[handle.addr as usize] {
+                continue;
+            }
+            marked[handle.addr as usize] = true;
 
-        match obj {
-            HeapValue::Free { .. } => unreachable!("that's not possible"),
-            HeapValue::Object(_) => unreachable!("nope. bad"),
-            HeapValue::Extern(extern_object) => extern_object,
+            if let Value::Object(handle) = value
+                && let Some(object) = self.get(handle)
+            {
+                worklist.extend(object.data.values().map(|slot| from_slot(*slot)));
+            }
         }
+
+        marked
     }
 
-    pub fn try_take_extern(&mut self, addr: u32) -> Option<ExternObject> {
-        let addr = addr as usize;
+    /// The error for using `handle` after its object was freed.
+    pub fn segfault(&self, handle: Handle) -> RuntimeError {
+        let cell = self.cells[handle.addr as usize];
+        // Only the most recent free is remembered, so if the cell has been reused and freed
+        // again since, we can't say when this handle's object went away.
+        let freed_in = if handle.generation.wrapping_add(1) == cell.generation {
+            cell.freed_in
+        } else {
+            None
+        };
 
-        if addr >= self.objects.len() {
-            panic!("bug: Invalid address");
+        RuntimeError::SegmentationFault {
+            addr: handle.addr,
+            freed_in,
         }
+    }
 
-        let prev_free = self.next_free;
-        let obj = std::mem::replace(&mut self.objects[addr], HeapValue::Free { next: prev_free });
-        self.next_free = addr;
+    /// Move an external object out of the heap, freeing its cell. Returns `None` if the
+    /// object has been freed.
+    pub fn take_extern(&mut self, handle: Handle) -> Option<ExternObject> {
+        if !self.is_live(handle) {
+            return None;
+        }
 
-        match obj {
-            HeapValue::Free { .. } => None,
-            HeapValue::Object(_) => None,
+        match self.release(handle.addr as usize, None) {
+            HeapValue::Free { .. } => unreachable!("that's not possible"),
+            HeapValue::Object(_) => unreachable!("nope. bad"),
             HeapValue::Extern(extern_object) => Some(extern_object),
         }
     }
 
-    pub fn insert<T: 'static>(&mut self, addr: u32, obj: T) {
-        let addr = addr as usize;
+    /// Put an external object back where [`Heap::take_extern`] took it from, so `handle`
+    /// is valid again.
+    pub fn insert<T: 'static>(&mut self, handle: Handle, obj: T) {
+        let addr = handle.addr as usize;
         let obj = ExternObject::new(obj);
 
-        if let HeapValue::Free { next } = self.objects[addr] {
+        // Taking the object bumped the generation once. If it's moved on since, the cell was
+        // reused, and bringing `handle` back would revive handles to whatever lived there.
+        assert!(
+            self.cells[addr].generation == handle.generation.wrapping_add(1),
+            "bug: inserting with a handle that wasn't just taken"
+        );
+
+        // Unlink the cell from the free list. Other cells may have been freed since it was
+        // taken, so it isn't necessarily at the front any more.
+        let HeapValue::Free { next } = self.objects[addr] else {
+            panic!("bug: inserting into a cell that's in use");
+        };
+        if self.next_free == addr {
             self.next_free = next;
+        } else {
+            let mut cell = self.next_free;
+            while let Some(HeapValue::Free { next: after }) = self.objects.get_mut(cell) {
+                if *after == addr {
+                    *after = next;
+                    break;
+                }
+                cell = *after;
+            }
         }
 
         self.objects[addr] = HeapValue::Extern(obj);
+        self.cells[addr].generation = handle.generation;
     }
 
     /// Returns `None` if the object has been freed.
-    pub fn get(&self, index: u32) -> Option<&Object> {
-        match &self.objects[index as usize] {
+    pub fn get(&self, handle: Handle) -> Option<&Object> {
+        if !self.is_live(handle) {
+            return None;
+        }
+
+        match &self.objects[handle.addr as usize] {
             HeapValue::Object(obj) => Some(obj),
             HeapValue::Free { .. } => None,
             HeapValue::Extern(_) => unreachable!("not an object"),
@@ -525,8 +948,12 @@ impl Heap {
     }
 
     /// Returns `None` if the object has been freed.
-    pub fn get_mut(&mut self, index: u32) -> Option<&mut Object> {
-        match &mut self.objects[index as usize] {
+    pub fn get_mut(&mut self, handle: Handle) -> Option<&mut Object> {
+        if !self.is_live(handle) {
+            return None;
+        }
+
+        match &mut self.objects[handle.addr as usize] {
             HeapValue::Object(obj) => Some(obj),
             HeapValue::Free { .. } => None,
             HeapValue::Extern(_) => unreachable!("not an object"),
@@ -534,8 +961,12 @@ impl Heap {
     }
 
     /// Returns `None` if the object has been freed.
-    pub fn get_extern(&self, index: u32) -> Option<&ExternObject> {
-        match &self.objects[index as usize] {
+    pub fn get_extern(&self, handle: Handle) -> Option<&ExternObject> {
+        if !self.is_live(handle) {
+            return None;
+        }
+
+        match &self.objects[handle.addr as usize] {
             HeapValue::Extern(obj) => Some(obj),
             HeapValue::Free { .. } => None,
             HeapValue::Object(_) => unreachable!("not an external object"),
@@ -543,31 +974,57 @@ impl Heap {
     }
 
     /// Returns `None` if the object has been freed.
-    pub fn get_extern_mut(&mut self, index: u32) -> Option<&mut ExternObject> {
-        match &mut self.objects[index as usize] {
+    pub fn get_extern_mut(&mut self, handle: Handle) -> Option<&mut ExternObject> {
+        if !self.is_live(handle) {
+            return None;
+        }
+
+        match &mut self.objects[handle.addr as usize] {
             HeapValue::Extern(obj) => Some(obj),
             HeapValue::Free { .. } => None,
             HeapValue::Object(_) => unreachable!("not an external object"),
         }
     }
 
-    pub fn free(&mut self, addr: u32) {
-        let addr = addr as usize;
+    /// Take the next object waiting for its finalizer, along with the finalizer.
+    pub fn next_finalizer(&mut self) -> Option<(Handle, u32)> {
+        self.finalizers.pop_front()
+    }
 
-        if addr >= self.objects.len() {
-            panic!("bug: Invalid address");
-        }
+    /// Put an object taken with [`Heap::next_finalizer`] back at the front of the queue, so
+    /// its finalizer is the next to run.
+    pub fn requeue_finalizer(&mut self, handle: Handle, func: u32) {
+        debug_assert!(self.cells[handle.addr as usize].finalizing);
+        self.finalizers.push_front((handle, func));
+    }
 
+    /// Free an object after its finalizer has run.
+    pub fn finish_finalizing(&mut self, handle: Handle) {
+        let addr = handle.addr as usize;
+        debug_assert!(self.cells[addr].finalizing);
+
+        self.cells[addr].finalizing = false;
+        self.release(addr, self.cells[addr].freed_in);
+    }
+
+    /// Put a cell on the free list and invalidate every handle to it. Returns what was in it.
+    fn release(&mut self, addr: usize, freed_in: Option<usize>) -> HeapValue {
         let prev_free = self.next_free;
-        self.objects[addr] = HeapValue::Free { next: prev_free };
+        let value = std::mem::replace(&mut self.objects[addr], HeapValue::Free { next: prev_free });
         self.next_free = addr;
+
+        let cell = &mut self.cells[addr];
+        cell.generation = cell.generation.wrapping_add(1);
+        cell.freed_in = freed_in;
+
+        value
     }
 }
 
 pub struct HeapIter<'h> {
     heap: &'h Heap,
     next_item: usize,
-    object_iter: Option<Iter<'h, u32, Value>>,
+    object_iter: Option<Iter<'h, u32, Slot>>,
 }
 
 pub struct HeapEntry<'h> {
@@ -586,7 +1043,7 @@ impl<'h> Iterator for HeapIter<'h> {
                 if let Some((_key, value)) = iter.next() {
                     // Yeah, there are. Return the next entry.
                     return Some(HeapEntry {
-                        value: value.to_u64(),
+                        value: from_slot(*value).to_u64(),
                         marker: Default::default(),
                     });
                 } else {
@@ -633,14 +1090,179 @@ pub enum ControlFlow {
     Halt,
 }
 
+/// Why [`Vm::run`] returned.
+#[derive(Debug)]
+pub enum StopReason {
+    Halt,
+    /// Used up all the fuel. Call `run` again to keep going.
+    OutOfFuel,
+    /// Out of memory. Collect garbage, then call `run` again to retry the instruction.
+    RequestGC,
+    Error(RuntimeError),
+    /// About to execute the instruction at this address, which has a breakpoint.
+    Breakpoint(usize),
+}
+
+/// Something a script did that it can't recover from. The instruction pointer is left on the
+/// instruction that failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    /// Tried to get or set a field on something that isn't an object.
+    NotAnObject(Value),
+    /// Tried to use an object after it was freed. `freed_in` is the GC cycle that freed it,
+    /// if that's known.
+    SegmentationFault {
+        addr: u32,
+        freed_in: Option<usize>,
+    },
+    /// Tried to call something that isn't a function.
+    NotAFunction(Value),
+    WrongArgumentCount {
+        expected: u8,
+        got: u8,
+    },
+    /// Called a method with `object.name(...)`. Method calls compile, but nothing dispatches
+    /// them yet.
+    MethodCall,
+    /// The heap is still full after a GC cycle and can't grow any more.
+    OutOfMemory,
+}
+
+/// A [`Module`] that has been linked into a runtime with [`Runtime::load`]. Global and field
+/// operands are that runtime's ids, and the constant pool holds ready to use values. Only
+/// valid for the runtime that loaded it.
+#[derive(Debug)]
+pub struct Program {
+    pub code: Vec<Instruction>,
+    pub lines: Vec<usize>,
+    pub constants: Vec<Value>,
+    /// Addresses [`Vm::run`] stops at.
+    pub breakpoints: ahash::HashSet<usize>,
+}
+
 pub struct Vm<'a> {
     pub vm: &'a mut Runtime,
-    pub module: &'a Module,
+    pub program: &'a Program,
+    /// Number of instructions executed by [`Vm::run`] so far.
+    pub steps: u64,
 }
 
 impl<'a> Vm<'a> {
-    pub fn step(&mut self) -> ControlFlow {
-        let inst = self.module.code[self.vm.ip];
+    /// Execute up to `fuel` instructions. Stops early if the program halts, needs a GC cycle,
+    /// fails, or reaches a breakpoint. The instruction the VM is on when `run` is called
+    /// never counts as a breakpoint, so calling `run` again steps past it.
+    ///
+    /// Any finalizers queued by the last GC cycle run first.
+    pub fn run(&mut self, fuel: u64) -> StopReason {
+        match self.run_finalizers() {
+            Ok(ControlFlow::RequestGC) => return StopReason::RequestGC,
+            Ok(_) => {}
+            Err(error) => return StopReason::Error(error),
+        }
+
+        // Checking for breakpoints costs a hash lookup per instruction, so programs without
+        // any get a loop that doesn't look.
+        let result = if self.program.breakpoints.is_empty() {
+            self.run_loop::<false>(fuel)
+        } else {
+            self.run_loop::<true>(fuel)
+        };
+
+        result.unwrap_or_else(|error| {
+            self.vm.ip -= 1;
+            StopReason::Error(*error)
+        })
+    }
+
+    /// The body of [`Vm::run`]. Errors are passed straight up, so the hot loop only has to
+    /// check for `Continue`.
+    fn run_loop<const BREAKPOINTS: bool>(
+        &mut self,
+        fuel: u64,
+    ) -> Result<StopReason, Box<RuntimeError>> {
+        for n in 0..fuel {
+            if BREAKPOINTS && n > 0 && self.program.breakpoints.contains(&self.vm.ip) {
+                return Ok(StopReason::Breakpoint(self.vm.ip));
+            }
+
+            self.steps += 1;
+            match self.execute()? {
+                ControlFlow::Continue => {}
+                ControlFlow::Halt => return Ok(StopReason::Halt),
+                ControlFlow::RequestGC => return Ok(StopReason::RequestGC),
+            }
+        }
+
+        Ok(StopReason::OutOfFuel)
+    }
+
+    /// Call the finalizer of every object the heap has queued, then free the object. Returns
+    /// `RequestGC` if a finalizer ran out of memory. It's called again once the GC cycle is
+    /// over.
+    fn run_finalizers(&mut self) -> Result<ControlFlow, RuntimeError> {
+        while let Some((handle, func)) = self.vm.heap.next_finalizer() {
+            let def = &self.vm.functions[func as usize];
+            if def.args != 1 {
+                self.vm.heap.finish_finalizing(handle);
+                return Err(RuntimeError::WrongArgumentCount {
+                    expected: def.args,
+                    got: 1,
+                });
+            }
+
+            let stack_len = self.vm.stack.len();
+            self.vm.stack.push(Value::Object(handle));
+
+            let mut needs_gc = false;
+            let mut error = None;
+            let func_args = FunctionArgs {
+                stack: &mut self.vm.stack,
+                heap: &mut self.vm.heap,
+                strings: &mut self.vm.interner,
+                field_to_id_map: &mut self.vm.field_to_id_map,
+                needs_gc: &mut needs_gc,
+                error: &mut error,
+            };
+            (def.func)(func_args);
+
+            self.vm.stack.truncate(stack_len);
+
+            if needs_gc && error.is_none() {
+                if self.vm.heap.is_exhausted() {
+                    self.vm.heap.finish_finalizing(handle);
+                    return Err(RuntimeError::OutOfMemory);
+                }
+
+                // The object stays queued, so it's still alive for the retry.
+                self.vm.heap.requeue_finalizer(handle, func);
+                return Ok(ControlFlow::RequestGC);
+            }
+
+            self.vm.heap.finish_finalizing(handle);
+
+            if let Some(error) = error {
+                return Err(error);
+            }
+        }
+
+        Ok(ControlFlow::Continue)
+    }
+
+    /// Execute a single instruction. On an error the instruction pointer is left on the
+    /// instruction that failed.
+    pub fn step(&mut self) -> Result<ControlFlow, RuntimeError> {
+        self.execute().map_err(|error| {
+            self.vm.ip -= 1;
+            *error
+        })
+    }
+
+    /// Inlined so that [`Vm::run`]'s loop branches straight to its error path instead of
+    /// going through a returned `Result` on every instruction. Errors are boxed to keep that
+    /// path small.
+    #[inline(always)]
+    fn execute(&mut self) -> Result<ControlFlow, Box<RuntimeError>> {
+        let inst = self.program.code[self.vm.ip];
         self.vm.ip += 1;
 
         // println!("{:?}", inst);
@@ -656,30 +1278,26 @@ impl<'a> Vm<'a> {
                 self.vm.globals[index as usize] = new_value;
             }
             Instruction::IndexGet { index } => {
-                let value = self.vm.stack.pop().unwrap();
-                if let Value::Object(addr) = value {
-                    if let Some(obj) = self.vm.heap.get(addr) {
-                        let field_value = obj.data.get(&index).copied().unwrap_or(Value::Nil);
-                        self.vm.stack.push(field_value);
-                    } else {
-                        todo!("segfault (attempt to read freed object");
-                    }
-                } else {
-                    todo!("not an object; need real errors");
-                }
+                let object = self.vm.stack.pop().unwrap();
+                let value = self.object(object)?.get(index);
+                self.vm.stack.push(value);
             }
             Instruction::IndexSet { index } => {
                 let new_value = self.vm.stack.pop().unwrap();
-                let value = self.vm.stack.pop().unwrap();
-                if let Value::Object(addr) = value {
-                    if let Some(obj) = self.vm.heap.get_mut(addr) {
-                        obj.data.insert(index, new_value);
-                    } else {
-                        todo!("segfault (attempt to read freed object");
-                    }
-                } else {
-                    todo!("not an object; need real errors");
-                }
+                let object = self.vm.stack.pop().unwrap();
+                self.set_field(object, index, new_value)?;
+                // Field assignments are expressions that evaluate to the new value.
+                self.vm.stack.push(new_value);
+            }
+            Instruction::IndexSetPop { index } => {
+                let new_value = self.vm.stack.pop().unwrap();
+                let object = self.vm.stack.pop().unwrap();
+                self.set_field(object, index, new_value)?;
+            }
+            Instruction::LoadGlobalField { global, field } => {
+                let object = self.vm.globals[global as usize];
+                let value = self.object(object)?.get(field);
+                self.vm.stack.push(value);
             }
             Instruction::LoadNil => {
                 self.vm.stack.push(Value::Nil);
@@ -691,73 +1309,33 @@ impl<'a> Vm<'a> {
                 self.vm.stack.push(Value::Bool(false));
             }
             Instruction::LoadConst { index } => {
-                let num = self.module.constants[index as usize];
-                self.vm.stack.push(Value::Number(num));
-            }
-            Instruction::LoadString { index } => {
-                self.vm.stack.push(Value::String(index)); // That's it. That's the whole joke.
+                let value = self.program.constants[index as usize];
+                self.vm.stack.push(value);
             }
             Instruction::Alloc => {
                 match self.vm.heap.alloc() {
-                    Some(addr) => self.vm.stack.push(Value::Object(addr)),
+                    Some(handle) => self.vm.stack.push(Value::Object(handle)),
+                    None if self.vm.heap.is_exhausted() => {
+                        return Err(RuntimeError::OutOfMemory.into());
+                    }
                     None => {
                         // Repeat this instruction on the next step.
                         self.vm.ip -= 1;
-                        return ControlFlow::RequestGC;
+                        return Ok(ControlFlow::RequestGC);
                     }
                 }
             }
-            Instruction::Call { args } => {
-                let func_offset = self.vm.stack.len() - (args as usize + 1);
-                let func_ptr = self.vm.stack[func_offset];
-
-                if let Value::FunctionPtr(ptr) = func_ptr {
-                    let mut needs_gc = false;
-                    let func_args = FunctionArgs {
-                        stack: &mut self.vm.stack,
-                        heap: &mut self.vm.heap,
-                        strings: &mut self.vm.interner,
-                        field_to_id_map: &mut self.vm.field_to_id_map,
-                        needs_gc: &mut needs_gc,
-                    };
-
-                    let def = &self.vm.functions[ptr as usize];
-
-                    // Make sure we have the correct number of arguments.
-                    // TODO: we probably shouldn't panic.
-                    if def.args != args {
-                        if def.args > args {
-                            panic!(
-                                "missing arguments. Expected {} but only got {}",
-                                def.args, args
-                            );
-                        } else {
-                            panic!("Too many arguments. Expected {} but got {}", def.args, args);
-                        }
-                    }
-
-                    // Call the function.
-                    let res = (def.func)(func_args);
-
-                    // Check if the function requested a garbage collection cycle.
-                    if needs_gc {
-                        // Roll back the instruction pointer so that this call instruction will
-                        // be executed again after the garbage collection cycle finishes.
-                        self.vm.ip -= 1;
-                        return ControlFlow::RequestGC;
-                    }
-
-                    // Call successfully completed. Remove arguments from stack and push the result.
-                    self.vm.stack.truncate(func_offset);
-                    self.vm.stack.push(res);
-                } else {
-                    todo!("expected function pointer");
+            Instruction::Call { args } => return self.call(args),
+            Instruction::CallPop { args } => {
+                let flow = self.call(args)?;
+                if let ControlFlow::Continue = flow {
+                    self.vm.stack.pop();
                 }
+                return Ok(flow);
             }
             Instruction::Invoke { .. } => {
                 // TODO: dispatch methods.
-                // Hrm...
-                todo!()
+                return Err(RuntimeError::MethodCall.into());
             }
             Instruction::Jmp { addr } => {
                 self.vm.ip = self.vm.ip.saturating_add_signed(addr as isize);
@@ -776,12 +1354,610 @@ impl<'a> Vm<'a> {
                 self.vm.stack.pop();
             }
             Instruction::Halt => {
-                return ControlFlow::Halt;
+                // Stay on the `Halt` so stepping again doesn't run off the end.
+                self.vm.ip -= 1;
+                return Ok(ControlFlow::Halt);
             }
         }
 
         // println!("-> {:?}\n", self.vm.stack);
 
-        ControlFlow::Continue
+        Ok(ControlFlow::Continue)
+    }
+
+    /// Call the function below the top `args` values on the stack, replacing it and its
+    /// arguments with the result. Returns `RequestGC` if the function requested a GC cycle, in
+    /// which case the stack is left alone and the instruction pointer is rolled back so the
+    /// call is retried.
+    fn call(&mut self, args: u8) -> Result<ControlFlow, Box<RuntimeError>> {
+        let func_offset = self.vm.stack.len() - (args as usize + 1);
+        let func_ptr = self.vm.stack.get(func_offset);
+
+        if let Value::FunctionPtr(ptr) = func_ptr {
+            let mut needs_gc = false;
+            let mut error = None;
+            let func_args = FunctionArgs {
+                stack: &mut self.vm.stack,
+                heap: &mut self.vm.heap,
+                strings: &mut self.vm.interner,
+                field_to_id_map: &mut self.vm.field_to_id_map,
+                needs_gc: &mut needs_gc,
+                error: &mut error,
+            };
+
+            let def = &self.vm.functions[ptr as usize];
+
+            // Make sure we have the correct number of arguments.
+            if def.args != args {
+                return Err(Box::new(RuntimeError::WrongArgumentCount {
+                    expected: def.args,
+                    got: args,
+                }));
+            }
+
+            // Call the function.
+            let res = (def.func)(func_args);
+
+            if let Some(error) = error {
+                return Err(Box::new(error));
+            }
+
+            // Check if the function requested a garbage collection cycle.
+            if needs_gc {
+                if self.vm.heap.is_exhausted() {
+                    return Err(RuntimeError::OutOfMemory.into());
+                }
+
+                // Roll back the instruction pointer so that this call instruction will
+                // be executed again after the garbage collection cycle finishes.
+                self.vm.ip -= 1;
+                return Ok(ControlFlow::RequestGC);
+            }
+
+            // Call successfully completed. Remove arguments from stack.
+            self.vm.stack.truncate(func_offset);
+            self.vm.stack.push(res);
+            Ok(ControlFlow::Continue)
+        } else {
+            Err(RuntimeError::NotAFunction(func_ptr).into())
+        }
+    }
+
+    /// The object `value` points to, for reading a field. Returning the object instead of the
+    /// field's value keeps the `Value` from going through memory on every read.
+    fn object(&self, value: Value) -> Result<&Object, Box<RuntimeError>> {
+        match value {
+            Value::Object(handle) => match self.vm.heap.get(handle) {
+                Some(obj) => Ok(obj),
+                None => Err(self.field_error(value)),
+            },
+            _ => Err(self.field_error(value)),
+        }
+    }
+
+    /// Set a field of the object `object` points to.
+    fn set_field(
+        &mut self,
+        object: Value,
+        index: u32,
+        value: Value,
+    ) -> Result<(), Box<RuntimeError>> {
+        if let Value::Object(handle) = object
+            && let Some(obj) = self.vm.heap.get_mut(handle)
+        {
+            obj.set(index, value);
+            Ok(())
+        } else {
+            Err(self.field_error(object))
+        }
+    }
+
+    /// The error for getting or setting a field on `object`. Kept out of line so the field
+    /// instructions stay small.
+    #[cold]
+    #[inline(never)]
+    fn field_error(&self, object: Value) -> Box<RuntimeError> {
+        Box::new(match object {
+            Value::Object(handle) => self.vm.heap.segfault(handle),
+            _ => RuntimeError::NotAnObject(object),
+        })
+    }
+}
+
+#[cfg(test)]
+impl Runtime {
+    /// The value of each global in `names`, formatted so that two runs can be compared.
+    /// Objects list their fields by id, since the order of their fields isn't stable.
+    pub fn describe_globals(&self, names: &[String]) -> Vec<(String, String)> {
+        let describe = |value| match value {
+            Value::Object(handle) => {
+                let object = self
+                    .heap
+                    .get(handle)
+                    .expect("bug: global holds a freed object");
+                let mut fields: Vec<_> = object.data.iter().collect();
+                fields.sort_by_key(|(field, _)| **field);
+                let fields: Vec<_> = fields
+                    .into_iter()
+                    .map(|(_, slot)| self.format_value(from_slot(*slot)))
+                    .collect();
+                fields.join(", ")
+            }
+            value => self.format_value(value),
+        };
+
+        names
+            .iter()
+            .map(|name| {
+                let value = self.get_global(name).unwrap_or(Value::Nil);
+                (name.clone(), describe(value))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+
+    /// Counts how many times it's been dropped.
+    struct DropCounter(Rc<Cell<usize>>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    fn heap() -> Heap {
+        Heap::new(HeapConfig {
+            size: 4,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn sweep_drops_extern_objects_once() {
+        let drops = Rc::new(Cell::new(0));
+        let mut heap = heap();
+        let kept = heap.alloc_extern(DropCounter(drops.clone())).unwrap();
+        let freed = heap.alloc_extern(DropCounter(drops.clone())).unwrap();
+
+        let mut marked = vec![false; heap.size()];
+        marked[kept.addr as usize] = true;
+        heap.sweep(&marked);
+        assert_eq!(drops.get(), 1);
+        assert!(heap.get_extern(freed).is_none());
+
+        // The freed cell is skipped this time around.
+        heap.sweep(&marked);
+        assert_eq!(drops.get(), 1);
+
+        drop(heap);
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn moved_out_values_are_not_dropped_by_the_heap() {
+        let drops = Rc::new(Cell::new(0));
+        let mut heap = heap();
+        let handle = heap.alloc_extern(DropCounter(drops.clone())).unwrap();
+
+        let obj = heap.take_extern(handle).unwrap();
+        assert_eq!(drops.get(), 0);
+
+        // Asking for the wrong type hands the object back without dropping it.
+        let obj = obj.into_obj::<String>().unwrap_err();
+        assert_eq!(drops.get(), 0);
+
+        let value = obj.into_obj::<DropCounter>().ok().unwrap();
+        heap.sweep(&vec![false; heap.size()]);
+        assert_eq!(drops.get(), 0);
+
+        drop(value);
+        assert_eq!(drops.get(), 1);
+        drop(heap);
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn taken_objects_drop_once() {
+        let drops = Rc::new(Cell::new(0));
+        let mut heap = heap();
+        let handle = heap.alloc_extern(DropCounter(drops.clone())).unwrap();
+
+        drop(heap.take_extern(handle).unwrap());
+        assert_eq!(drops.get(), 1);
+
+        drop(heap);
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn insert_restores_a_taken_object() {
+        let drops = Rc::new(Cell::new(0));
+        let mut heap = heap();
+        let handle = heap.alloc_extern(DropCounter(drops.clone())).unwrap();
+        let other = heap.alloc().unwrap();
+
+        let value = heap
+            .take_extern(handle)
+            .unwrap()
+            .into_obj::<DropCounter>()
+            .ok()
+            .unwrap();
+
+        // Free something else in the meantime so the taken cell isn't first in the free list.
+        let mut marked = vec![true; heap.size()];
+        marked[other.addr as usize] = false;
+        heap.sweep(&marked);
+
+        heap.insert(handle, *value);
+        assert_eq!(drops.get(), 0);
+        assert!(heap.get_extern(handle).is_some());
+
+        // Every other cell can still be allocated, and the restored one isn't handed out.
+        for _ in 0..heap.size() - 1 {
+            assert_ne!(heap.alloc().unwrap().addr, handle.addr);
+        }
+        assert!(heap.alloc().is_none());
+
+        drop(heap);
+        assert_eq!(drops.get(), 1);
+    }
+
+    /// Point `from`'s field 1 at `to`.
+    fn link(heap: &mut Heap, from: Handle, to: Value) {
+        heap.get_mut(from).unwrap().set(1, to);
+    }
+
+    #[test]
+    fn trace_follows_cycles() {
+        let mut heap = heap();
+        let a = heap.alloc().unwrap();
+        let b = heap.alloc().unwrap();
+        let unreachable = heap.alloc().unwrap();
+        link(&mut heap, a, Value::Object(b));
+        link(&mut heap, b, Value::Object(a));
+        link(&mut heap, unreachable, Value::Object(a));
+
+        let marked = heap.trace([Value::Object(b)]);
+        assert!(marked[a.addr as usize]);
+        assert!(marked[b.addr as usize]);
+        assert!(!marked[unreachable.addr as usize]);
+    }
+
+    #[test]
+    fn trace_skips_stale_handles() {
+        let mut heap = heap();
+        let old = heap.alloc().unwrap();
+        heap.sweep(&vec![false; heap.size()]);
+
+        // The cell is reused, but a handle to the freed object doesn't keep the new one.
+        let new = heap.alloc().unwrap();
+        assert_eq!(new.addr, old.addr);
+        let root = heap.alloc().unwrap();
+        link(&mut heap, root, Value::Object(old));
+
+        let marked = heap.trace([Value::Object(root), Value::Object(old)]);
+        assert!(marked[root.addr as usize]);
+        assert!(!marked[new.addr as usize]);
+    }
+
+    #[test]
+    fn trace_marks_extern_objects_without_looking_inside() {
+        let mut heap = heap();
+        let object = heap.alloc().unwrap();
+        // Just bits to the tracer, even if they happen to look like a handle.
+        let opaque = heap.alloc_extern(Value::Object(object)).unwrap();
+        let root = heap.alloc().unwrap();
+        link(&mut heap, root, Value::ExternObject(opaque));
+
+        let marked = heap.trace([Value::Object(root), Value::Number(1.0), Value::Nil]);
+        assert!(marked[root.addr as usize]);
+        assert!(marked[opaque.addr as usize]);
+        assert!(!marked[object.addr as usize]);
+    }
+
+    /// A runtime with a one-argument native `on_free`, and the pointer to put in `finalize`
+    /// fields to make it an object's finalizer.
+    fn runtime_with_finalizer(
+        size: usize,
+        finalizer: impl Fn(FunctionArgs) -> Value + 'static,
+    ) -> (Runtime, Value) {
+        let mut runtime = Runtime::new(HeapConfig {
+            size,
+            ..Default::default()
+        });
+        runtime.register_function("on_free", 1, finalizer);
+        let func = runtime.get_global("on_free").unwrap();
+        (runtime, func)
+    }
+
+    fn alloc_finalizable(runtime: &mut Runtime, func: Value) -> Handle {
+        let handle = runtime.heap.alloc().unwrap();
+        runtime
+            .heap
+            .get_mut(handle)
+            .unwrap()
+            .set(FINALIZE_FIELD, func);
+        handle
+    }
+
+    fn program(code: Vec<Instruction>) -> Program {
+        Program {
+            lines: vec![1; code.len()],
+            code,
+            constants: vec![],
+            breakpoints: Default::default(),
+        }
+    }
+
+    #[test]
+    fn finalizers_run_before_their_object_is_freed() {
+        let calls = Rc::new(Cell::new(0));
+        let (mut runtime, func) = runtime_with_finalizer(4, {
+            let calls = calls.clone();
+            move |mut args| {
+                // The object and whatever it points to are still there.
+                let Some(Value::Object(handle)) = args.stack.pop() else {
+                    panic!("expected an object");
+                };
+                let child = args.field_id("child");
+                let Value::Object(child) = args.heap.get(handle).unwrap().get(child) else {
+                    panic!("expected a child");
+                };
+                assert!(args.heap.get(child).is_some());
+
+                calls.set(calls.get() + 1);
+                Value::Nil
+            }
+        });
+        let object = alloc_finalizable(&mut runtime, func);
+        let child = runtime.heap.alloc().unwrap();
+        let child_field = runtime.get_field_index("child");
+        runtime
+            .heap
+            .get_mut(object)
+            .unwrap()
+            .set(child_field, Value::Object(child));
+
+        // Nothing is freed yet: the object is queued and its child is kept for it.
+        assert_eq!(runtime.heap.sweep(&[false; 4]), 0);
+        assert_eq!(calls.get(), 0);
+
+        let halt = program(vec![Instruction::Halt]);
+        assert!(matches!(
+            runtime.spawn_vm(&halt).run(u64::MAX),
+            StopReason::Halt
+        ));
+        assert_eq!(calls.get(), 1);
+        assert!(runtime.heap.get(object).is_none());
+
+        // Now the child can go.
+        assert_eq!(runtime.heap.sweep(&[false; 4]), 1);
+    }
+
+    #[test]
+    fn objects_are_finalized_at_most_once() {
+        let calls = Rc::new(Cell::new(0));
+        let (mut runtime, func) = runtime_with_finalizer(4, {
+            let calls = calls.clone();
+            move |_| {
+                calls.set(calls.get() + 1);
+                Value::Nil
+            }
+        });
+        alloc_finalizable(&mut runtime, func);
+        let halt = program(vec![Instruction::Halt]);
+
+        // Queued objects aren't queued again by the next cycle.
+        runtime.heap.sweep(&[false; 4]);
+        runtime.heap.sweep(&[false; 4]);
+        runtime.spawn_vm(&halt).run(u64::MAX);
+        runtime.heap.sweep(&[false; 4]);
+        runtime.spawn_vm(&halt).run(u64::MAX);
+
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn finalized_objects_stay_freed() {
+        let saved = Rc::new(Cell::new(Value::Nil));
+        let (mut runtime, func) = runtime_with_finalizer(4, {
+            let saved = saved.clone();
+            move |args| {
+                saved.set(args.stack.pop().unwrap());
+                Value::Nil
+            }
+        });
+        let object = alloc_finalizable(&mut runtime, func);
+        runtime.heap.sweep(&[false; 4]);
+
+        let halt = program(vec![Instruction::Halt]);
+        runtime.spawn_vm(&halt).run(u64::MAX);
+
+        // The finalizer kept the object, but reading it is a use after free.
+        runtime.set_global("saved", saved.get());
+        let global = runtime.get_global_index("saved") as u32;
+        let read = program(vec![
+            Instruction::LoadGlobalField {
+                global,
+                field: FINALIZE_FIELD,
+            },
+            Instruction::Halt,
+        ]);
+        let StopReason::Error(error) = runtime.spawn_vm(&read).run(u64::MAX) else {
+            panic!("expected a segfault");
+        };
+        assert_eq!(
+            error,
+            RuntimeError::SegmentationFault {
+                addr: object.addr,
+                freed_in: Some(1),
+            }
+        );
+    }
+
+    #[test]
+    fn finalizers_that_run_out_of_memory_wait_for_a_gc_cycle() {
+        let finished = Rc::new(Cell::new(false));
+        let (mut runtime, func) = runtime_with_finalizer(4, {
+            let finished = finished.clone();
+            move |args| {
+                // Needs two objects. If only the first fits, it's garbage by the retry.
+                if args.heap.alloc().is_some() && args.heap.alloc().is_some() {
+                    finished.set(true);
+                } else {
+                    *args.needs_gc = true;
+                }
+                Value::Nil
+            }
+        });
+        let object = alloc_finalizable(&mut runtime, func);
+        let kept = runtime.heap.alloc().unwrap();
+        let kept_for_now = runtime.heap.alloc().unwrap();
+        runtime.heap.alloc().unwrap();
+
+        let mut marked = [false; 4];
+        marked[kept.addr as usize] = true;
+        marked[kept_for_now.addr as usize] = true;
+        assert_eq!(runtime.heap.sweep(&marked), 1);
+
+        let halt = program(vec![Instruction::Halt]);
+        assert!(matches!(
+            runtime.spawn_vm(&halt).run(u64::MAX),
+            StopReason::RequestGC
+        ));
+        assert!(!finished.get());
+        assert!(runtime.heap.get(object).is_some());
+
+        marked[kept_for_now.addr as usize] = false;
+        runtime.heap.sweep(&marked);
+        assert!(matches!(
+            runtime.spawn_vm(&halt).run(u64::MAX),
+            StopReason::Halt
+        ));
+        assert!(finished.get());
+        assert!(runtime.heap.get(object).is_none());
+    }
+
+    #[test]
+    fn heap_grows_up_to_its_max_size() {
+        let mut heap = Heap::new(HeapConfig {
+            size: 2,
+            grow_below: Some(0.5),
+            max_size: 5,
+        });
+        let mut live = vec![];
+
+        // Each cycle that frees less than half the heap doubles it, but never past 5.
+        for size in [4, 5, 5] {
+            while let Some(handle) = heap.alloc() {
+                live.push(handle);
+            }
+            assert_eq!(heap.sweep(&vec![true; heap.size()]), 0);
+            assert_eq!(heap.size(), size);
+        }
+
+        assert!(heap.alloc().is_none());
+        assert!(heap.is_exhausted());
+
+        // A cycle that frees enough doesn't grow the heap.
+        let mut marked = vec![false; heap.size()];
+        marked[live[0].addr as usize] = true;
+        assert_eq!(heap.sweep(&marked), 4);
+        assert_eq!(heap.size(), 5);
+        assert!(!heap.is_exhausted());
+    }
+
+    #[test]
+    fn alloc_fails_once_the_heap_cannot_grow() {
+        let mut runtime = Runtime::new(HeapConfig {
+            size: 1,
+            grow_below: Some(0.5),
+            max_size: 2,
+        });
+        // Every object stays on the stack, so no cycle can free anything.
+        let allocs = program(vec![
+            Instruction::Alloc,
+            Instruction::Alloc,
+            Instruction::Alloc,
+            Instruction::Halt,
+        ]);
+        let mut vm = runtime.spawn_vm(&allocs);
+
+        // The second ALLOC gets room by growing the heap.
+        assert!(matches!(vm.run(u64::MAX), StopReason::RequestGC));
+        vm.vm.collect_garbage();
+        assert_eq!(vm.vm.heap.size(), 2);
+
+        // The third can't, since the heap is at its max size.
+        assert!(matches!(vm.run(u64::MAX), StopReason::RequestGC));
+        vm.vm.collect_garbage();
+        assert_eq!(vm.vm.heap.size(), 2);
+        assert!(matches!(
+            vm.run(u64::MAX),
+            StopReason::Error(RuntimeError::OutOfMemory)
+        ));
+        assert_eq!(vm.vm.ip, 2);
+    }
+
+    #[test]
+    fn segfaults_know_which_cycle_freed_the_object() {
+        let mut heap = heap();
+        let handle = heap.alloc().unwrap();
+        heap.sweep(&vec![false; heap.size()]);
+
+        assert_eq!(
+            heap.segfault(handle),
+            RuntimeError::SegmentationFault {
+                addr: handle.addr,
+                freed_in: Some(1),
+            }
+        );
+    }
+
+    #[test]
+    fn segfaults_forget_the_cycle_once_the_cell_is_freed_again() {
+        let mut heap = heap();
+        let old = heap.alloc().unwrap();
+        heap.sweep(&vec![false; heap.size()]);
+
+        // The freed cell is at the front of the free list, so it's reused straight away.
+        let new = heap.alloc().unwrap();
+        assert_eq!(new.addr, old.addr);
+        heap.sweep(&vec![false; heap.size()]);
+
+        assert_eq!(
+            heap.segfault(old),
+            RuntimeError::SegmentationFault {
+                addr: old.addr,
+                freed_in: None,
+            }
+        );
+        assert_eq!(
+            heap.segfault(new),
+            RuntimeError::SegmentationFault {
+                addr: new.addr,
+                freed_in: Some(2),
+            }
+        );
+    }
+
+    #[test]
+    fn stale_handles_take_nothing() {
+        let drops = Rc::new(Cell::new(0));
+        let mut heap = heap();
+        let old = heap.alloc_extern(DropCounter(drops.clone())).unwrap();
+        heap.sweep(&vec![false; heap.size()]);
+        let new = heap.alloc_extern(DropCounter(drops.clone())).unwrap();
+        assert_eq!(new.addr, old.addr);
+
+        assert!(heap.take_extern(old).is_none());
+        assert!(heap.get_extern(new).is_some());
+        assert_eq!(drops.get(), 1);
     }
 }
@@ -0,0 +1,190 @@
+//! NaN-boxed values: a [`Value`] packed into a single 64-bit word.
+//!
+//! Numbers are stored as their own bits. Every other value hides inside a quiet NaN with a
+//! non-zero tag. The only NaN a number can be is [`f64::NAN`] (any other NaN is
+//! canonicalized to it on the way in), which has a tag of zero:
+//!
+//! ```text
+//!  63  62..52   51   50..48  47..32       31..0
+//! ┌───┬───────┬───┬───────┬────────────┬─────────┐
+//! │ 0 │ 1...1 │ 1 │  tag  │ generation │ payload │
+//! └───┴───────┴───┴───────┴────────────┴─────────┘
+//! ```
+//!
+//! The payload is the address (or `0`/`1` for booleans) and the generation is the
+//! [`Handle`](crate::vm::Handle)'s, so unlike [`Value::to_u64`] used to, an `Object` and a
+//! `String` at the same address never share the same bits.
+
+use crate::vm::{Handle, Value};
+
+/// Exponent all ones plus the quiet bit.
+const QNAN: u64 = 0x7ff8_0000_0000_0000;
+
+const TAG_SHIFT: u32 = 48;
+const TAG_MASK: u64 = 0b111;
+const GENERATION_SHIFT: u32 = 32;
+
+const TAG_NIL: u64 = 1;
+const TAG_BOOL: u64 = 2;
+const TAG_STRING: u64 = 3;
+const TAG_FUNCTION_PTR: u64 = 4;
+const TAG_OBJECT: u64 = 5;
+const TAG_EXTERN_OBJECT: u64 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct PackedValue(u64);
+
+impl PackedValue {
+    pub fn pack(value: Value) -> Self {
+        let (tag, generation, payload) = match value {
+            Value::Number(num) if num.is_nan() => return Self(f64::NAN.to_bits()),
+            Value::Number(num) => return Self(num.to_bits()),
+            Value::Nil => (TAG_NIL, 0, 0),
+            Value::Bool(bool) => (TAG_BOOL, 0, bool as u32),
+            Value::String(addr) => (TAG_STRING, 0, addr),
+            Value::FunctionPtr(addr) => (TAG_FUNCTION_PTR, 0, addr),
+            Value::Object(handle) => (TAG_OBJECT, handle.generation, handle.addr),
+            Value::ExternObject(handle) => (TAG_EXTERN_OBJECT, handle.generation, handle.addr),
+        };
+
+        Self(QNAN | tag << TAG_SHIFT | (generation as u64) << GENERATION_SHIFT | payload as u64)
+    }
+
+    pub fn unpack(self) -> Value {
+        let tag = self.0 >> TAG_SHIFT & TAG_MASK;
+        if self.0 & QNAN != QNAN || tag == 0 {
+            return Value::Number(f64::from_bits(self.0));
+        }
+
+        let payload = self.0 as u32;
+        let handle = || Handle {
+            addr: payload,
+            generation: (self.0 >> GENERATION_SHIFT) as u16,
+        };
+        match tag {
+            TAG_NIL => Value::Nil,
+            TAG_BOOL => Value::Bool(payload != 0),
+            TAG_STRING => Value::String(payload),
+            TAG_FUNCTION_PTR => Value::FunctionPtr(payload),
+            TAG_OBJECT => Value::Object(handle()),
+            TAG_EXTERN_OBJECT => Value::ExternObject(handle()),
+            tag => unreachable!("bug: invalid value tag {tag}"),
+        }
+    }
+
+    pub fn to_bits(self) -> u64 {
+        self.0
+    }
+}
+
+impl From<Value> for PackedValue {
+    fn from(value: Value) -> Self {
+        Self::pack(value)
+    }
+}
+
+impl From<PackedValue> for Value {
+    fn from(value: PackedValue) -> Self {
+        value.unpack()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(value: Value) -> Value {
+        PackedValue::pack(value).unpack()
+    }
+
+    #[test]
+    fn numbers_keep_their_bits() {
+        for num in [
+            0.0,
+            -0.0,
+            1.5,
+            -1e300,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            // Subnormals.
+            f64::from_bits(1),
+            -f64::from_bits(0x000f_ffff_ffff_ffff),
+        ] {
+            let Value::Number(unpacked) = round_trip(Value::Number(num)) else {
+                panic!("{num} didn't come back as a number");
+            };
+            assert_eq!(unpacked.to_bits(), num.to_bits(), "{num}");
+        }
+    }
+
+    #[test]
+    fn every_nan_is_a_number() {
+        // Including NaNs whose bits look like a tagged value.
+        let tagged = PackedValue::pack(Value::Object(Handle {
+            addr: 7,
+            generation: 3,
+        }));
+        for nan in [
+            f64::NAN,
+            -f64::NAN,
+            f64::from_bits(0x7ff0_0000_0000_0001),
+            f64::from_bits(tagged.to_bits()),
+        ] {
+            let packed = PackedValue::pack(Value::Number(nan));
+            assert_eq!(packed.to_bits(), f64::NAN.to_bits());
+            assert!(matches!(packed.unpack(), Value::Number(num) if num.is_nan()));
+        }
+    }
+
+    #[test]
+    fn other_values_round_trip() {
+        let largest = Handle {
+            addr: u32::MAX,
+            generation: u16::MAX,
+        };
+        for value in [
+            Value::Nil,
+            Value::Bool(false),
+            Value::Bool(true),
+            Value::String(0),
+            Value::String(u32::MAX),
+            Value::FunctionPtr(0),
+            Value::FunctionPtr(u32::MAX),
+            Value::Object(Handle {
+                addr: 0,
+                generation: 0,
+            }),
+            Value::Object(largest),
+            Value::ExternObject(largest),
+        ] {
+            assert_eq!(round_trip(value), value);
+        }
+    }
+
+    #[test]
+    fn values_at_the_same_address_differ() {
+        let handle = Handle {
+            addr: 5,
+            generation: 0,
+        };
+        let values = [
+            Value::Number(f64::from_bits(5)),
+            Value::String(5),
+            Value::FunctionPtr(5),
+            Value::Object(handle),
+            Value::ExternObject(handle),
+            Value::Object(Handle {
+                generation: 1,
+                ..handle
+            }),
+        ];
+        for (i, a) in values.iter().enumerate() {
+            for b in &values[i + 1..] {
+                assert_ne!(PackedValue::pack(*a), PackedValue::pack(*b), "{a:?} {b:?}");
+            }
+        }
+    }
+}
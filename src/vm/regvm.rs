@@ -0,0 +1,856 @@
+//! Prototype register-based VM.
+//!
+//! [`Vm`](super::Vm) is a stack machine: every value goes through the stack, so
+//! `i = add(i, 1)` takes five instructions (load `add`, load `i`, load `1`, call, store `i`),
+//! each its own call to `step`. Here instructions name their operands and destination
+//! directly, as slots in a frame of registers:
+//!
+//! - Every global the module uses gets a register for the whole run. Native functions can't
+//!   see globals, so they're loaded from the runtime when the VM starts (or resumes after a
+//!   GC cycle) and written back when it stops. Reading a variable costs no instructions.
+//! - Temporaries are allocated above the globals, like a stack, so the arguments of a call
+//!   always sit in consecutive registers at the top of the frame. That's where native
+//!   functions expect their arguments anyway, so calls don't copy anything.
+//! - [`RegVm::run`] executes instructions in a loop until the program halts, fails or needs
+//!   a GC cycle, instead of being called once per instruction.
+//!
+//! With that, `i = add(i, 1)` is `Move t0, i; LoadConst t1, 1; Call i, add(t0, t1)`.
+//!
+//! The frame lives outside the runtime, so the GC can't see it by itself: an object held only
+//! in a temporary would be freed out from under the frame. That's why a
+//! [`ControlFlow::RequestGC`] from `RegVm` has to be serviced with
+//! [`RegVm::collect_garbage`], which makes the registers roots for the cycle.
+//!
+//! Modules are compiled straight from the AST with [`compile`]. Use `nac bench` to compare
+//! against the stack VM.
+
+use crate::{
+    Error,
+    ast::{Branch, Expr, Stmt},
+    compiler::{Constant, ConstantPool, NameTable},
+    gc::CycleScore,
+    vm::{ControlFlow, FunctionArgs, Runtime, RuntimeError, Stack, Value},
+};
+
+/// A slot in the frame. Globals come first, then temporaries.
+pub type Reg = u16;
+
+#[derive(Debug, Clone, Copy)]
+pub enum RegInstruction {
+    LoadNil {
+        dst: Reg,
+    },
+    LoadBool {
+        dst: Reg,
+        value: bool,
+    },
+    LoadConst {
+        dst: Reg,
+        index: u32,
+    },
+    Move {
+        dst: Reg,
+        src: Reg,
+    },
+    Alloc {
+        dst: Reg,
+    },
+    GetField {
+        dst: Reg,
+        object: Reg,
+        field: u32,
+    },
+    SetField {
+        object: Reg,
+        field: u32,
+        src: Reg,
+    },
+    // Call the function in `callee` with the `args` registers starting at `base`. Nothing
+    // above the arguments may be in use.
+    Call {
+        dst: Reg,
+        callee: Reg,
+        base: Reg,
+        args: u8,
+    },
+    // Invoke a method.
+    Invoke {
+        dst: Reg,
+        object: Reg,
+        sym: u32,
+        base: Reg,
+        args: u8,
+    },
+    Jmp {
+        addr: i32,
+    },
+    JmpIfFalse {
+        cond: Reg,
+        addr: i32,
+    },
+    Halt,
+}
+
+/// Compiled register bytecode. Like [`Module`](crate::compiler::Module), globals, fields and
+/// strings are referred to by name until the module is [loaded](load).
+#[derive(Debug)]
+pub struct RegModule {
+    pub code: Vec<RegInstruction>,
+    pub constants: Vec<Constant>,
+    /// Names of the globals. Global `n` lives in register `n`.
+    pub globals: Vec<String>,
+    pub fields: Vec<String>,
+    /// Size of the frame.
+    pub registers: usize,
+}
+
+pub fn compile(program: &[Stmt]) -> Result<RegModule, Error> {
+    // Temporaries go above the globals, so every global has to be known up front.
+    let mut globals = NameTable::default();
+    for stmt in program {
+        collect_globals(stmt, &mut globals);
+    }
+
+    let first_temp = globals.names.len();
+    debug_assert!(first_temp <= Reg::MAX as usize, "bug: too many globals");
+
+    let mut compiler = RegCompiler {
+        code: vec![],
+        constants: Default::default(),
+        globals,
+        fields: Default::default(),
+        next_temp: first_temp as Reg,
+        registers: first_temp,
+    };
+
+    for stmt in program {
+        compiler.compile_statement(stmt)?;
+    }
+
+    compiler.code.push(RegInstruction::Halt);
+
+    Ok(RegModule {
+        code: compiler.code,
+        constants: compiler.constants.constants,
+        globals: compiler.globals.names,
+        fields: compiler.fields.names,
+        registers: compiler.registers,
+    })
+}
+
+fn collect_globals(stmt: &Stmt, globals: &mut NameTable) {
+    match stmt {
+        Stmt::Expr { expr, .. } => collect_expr_globals(expr, globals),
+        Stmt::If {
+            branches,
+            else_body,
+            ..
+        } => {
+            for branch in branches {
+                collect_expr_globals(&branch.cond, globals);
+                branch
+                    .body
+                    .iter()
+                    .for_each(|stmt| collect_globals(stmt, globals));
+            }
+            for stmt in else_body.iter().flatten() {
+                collect_globals(stmt, globals);
+            }
+        }
+        Stmt::While { cond, body, .. } => {
+            collect_expr_globals(cond, globals);
+            body.iter().for_each(|stmt| collect_globals(stmt, globals));
+        }
+    }
+}
+
+fn collect_expr_globals(expr: &Expr, globals: &mut NameTable) {
+    match expr {
+        Expr::Nil(_) | Expr::Bool(..) | Expr::Number(..) | Expr::String(..) | Expr::Alloc(_) => {}
+        Expr::Var { name, .. } => {
+            globals.index(name);
+        }
+        Expr::Assign { name, value, .. } => {
+            globals.index(name);
+            collect_expr_globals(value, globals);
+        }
+        Expr::Call { callee, args, .. } => {
+            collect_expr_globals(callee, globals);
+            args.iter()
+                .for_each(|arg| collect_expr_globals(arg, globals));
+        }
+        Expr::Field { object, .. } => collect_expr_globals(object, globals),
+        Expr::FieldAssign { object, value, .. } => {
+            collect_expr_globals(object, globals);
+            collect_expr_globals(value, globals);
+        }
+        Expr::Invoke { object, args, .. } => {
+            collect_expr_globals(object, globals);
+            args.iter()
+                .for_each(|arg| collect_expr_globals(arg, globals));
+        }
+    }
+}
+
+/// Whether evaluating `expr` can change a global. Native functions can't, so only
+/// assignments do.
+fn assigns_global(expr: &Expr) -> bool {
+    match expr {
+        Expr::Assign { .. } => true,
+        Expr::Nil(_)
+        | Expr::Bool(..)
+        | Expr::Number(..)
+        | Expr::String(..)
+        | Expr::Alloc(_)
+        | Expr::Var { .. } => false,
+        Expr::Call { callee, args, .. } => {
+            assigns_global(callee) || args.iter().any(assigns_global)
+        }
+        Expr::Field { object, .. } => assigns_global(object),
+        Expr::FieldAssign { object, value, .. } => assigns_global(object) || assigns_global(value),
+        Expr::Invoke { object, args, .. } => {
+            assigns_global(object) || args.iter().any(assigns_global)
+        }
+    }
+}
+
+struct RegCompiler {
+    code: Vec<RegInstruction>,
+    constants: ConstantPool,
+    globals: NameTable,
+    fields: NameTable,
+    /// The lowest free temporary. Everything from here up is unused.
+    next_temp: Reg,
+    /// The most registers in use at once so far.
+    registers: usize,
+}
+
+impl RegCompiler {
+    fn emit(&mut self, inst: RegInstruction) -> usize {
+        self.code.push(inst);
+        self.code.len() - 1
+    }
+
+    /// Point the jump at `addr` to the next instruction that will be emitted.
+    fn patch_jump(&mut self, addr: usize) {
+        // Jumps are relative to the instruction following the jump.
+        let offset = (self.code.len() - addr - 1) as i32;
+        self.code[addr] = match self.code[addr] {
+            RegInstruction::Jmp { .. } => RegInstruction::Jmp { addr: offset },
+            RegInstruction::JmpIfFalse { cond, .. } => {
+                RegInstruction::JmpIfFalse { cond, addr: offset }
+            }
+            inst => unreachable!("bug: {inst:?} is not a jump"),
+        };
+    }
+
+    fn temp(&mut self) -> Reg {
+        let reg = self.next_temp;
+        debug_assert!(reg < Reg::MAX, "bug: too many registers");
+        self.next_temp += 1;
+        self.registers = self.registers.max(self.next_temp as usize);
+        reg
+    }
+
+    fn global(&mut self, name: &str) -> Reg {
+        self.globals.index(name) as Reg
+    }
+
+    fn compile_statement(&mut self, stmt: &Stmt) -> Result<(), Error> {
+        // Temporaries don't outlive the statement that needed them.
+        let mark = self.next_temp;
+
+        match stmt {
+            Stmt::Expr { expr, .. } => self.compile_effect(expr)?,
+            Stmt::If {
+                branches,
+                else_body,
+                ..
+            } => self.compile_if_stmt(branches, else_body.as_deref())?,
+            Stmt::While { cond, body, .. } => {
+                let start = self.code.len();
+                let cond = self.compile_expr(cond)?;
+                let exit_jump = self.emit(RegInstruction::JmpIfFalse { cond, addr: 0 });
+                self.next_temp = mark;
+
+                for stmt in body {
+                    self.compile_statement(stmt)?;
+                }
+
+                let end = self.code.len();
+                self.emit(RegInstruction::Jmp {
+                    addr: start as i32 - end as i32 - 1,
+                });
+                self.patch_jump(exit_jump);
+            }
+        }
+
+        self.next_temp = mark;
+        Ok(())
+    }
+
+    fn compile_if_stmt(
+        &mut self,
+        branches: &[Branch],
+        else_body: Option<&[Stmt]>,
+    ) -> Result<(), Error> {
+        // Same layout as the stack compiler's.
+        let mark = self.next_temp;
+        let mut exit_jumps = vec![];
+
+        for (n, branch) in branches.iter().enumerate() {
+            let cond = self.compile_expr(&branch.cond)?;
+            let next_branch = self.emit(RegInstruction::JmpIfFalse { cond, addr: 0 });
+            self.next_temp = mark;
+
+            for stmt in branch.body.iter() {
+                self.compile_statement(stmt)?;
+            }
+
+            let is_last = n == branches.len() - 1 && else_body.is_none();
+            if !is_last {
+                exit_jumps.push(self.emit(RegInstruction::Jmp { addr: 0 }));
+            }
+
+            self.patch_jump(next_branch);
+        }
+
+        for stmt in else_body.into_iter().flatten() {
+            self.compile_statement(stmt)?;
+        }
+
+        for jump in exit_jumps {
+            self.patch_jump(jump);
+        }
+
+        Ok(())
+    }
+
+    /// Compile an expression whose value isn't used.
+    fn compile_effect(&mut self, expr: &Expr) -> Result<(), Error> {
+        match expr {
+            // No side effects, nothing to do.
+            Expr::Nil(_)
+            | Expr::Bool(..)
+            | Expr::Number(..)
+            | Expr::String(..)
+            | Expr::Var { .. } => {}
+            Expr::Assign { name, value, .. } => {
+                let global = self.global(name);
+                self.compile_expr_into(value, global)?;
+            }
+            Expr::FieldAssign {
+                object,
+                name,
+                value,
+                ..
+            } => {
+                self.compile_field_assign(object, name, value)?;
+            }
+            _ => {
+                let dst = self.temp();
+                self.compile_expr_into(expr, dst)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compile an expression, returning the register its value ends up in. That's the
+    /// variable's own register for a plain variable read, otherwise a new temporary.
+    fn compile_expr(&mut self, expr: &Expr) -> Result<Reg, Error> {
+        if let Expr::Var { name, .. } = expr {
+            return Ok(self.global(name));
+        }
+
+        let dst = self.temp();
+        self.compile_expr_into(expr, dst)?;
+        Ok(dst)
+    }
+
+    /// Like [`RegCompiler::compile_expr`], except the value always goes in a new temporary.
+    /// Use this for operands that have to survive evaluating a later operand which might
+    /// reassign the variable they were read from.
+    fn compile_expr_copy(&mut self, expr: &Expr) -> Result<Reg, Error> {
+        let dst = self.temp();
+        self.compile_expr_into(expr, dst)?;
+        Ok(dst)
+    }
+
+    /// Compile an expression, putting its value in `dst`.
+    fn compile_expr_into(&mut self, expr: &Expr, dst: Reg) -> Result<(), Error> {
+        let mark = self.next_temp;
+
+        match expr {
+            Expr::Nil(_) => {
+                self.emit(RegInstruction::LoadNil { dst });
+            }
+            Expr::Bool(value, _) => {
+                self.emit(RegInstruction::LoadBool { dst, value: *value });
+            }
+            Expr::Number(num, _) => {
+                let index = self.constants.add(Constant::Number(*num));
+                self.emit(RegInstruction::LoadConst { dst, index });
+            }
+            Expr::String(value, _) => {
+                let index = self.constants.add(Constant::String(value.clone()));
+                self.emit(RegInstruction::LoadConst { dst, index });
+            }
+            Expr::Alloc(_) => {
+                self.emit(RegInstruction::Alloc { dst });
+            }
+            Expr::Var { name, .. } => {
+                let src = self.global(name);
+                if src != dst {
+                    self.emit(RegInstruction::Move { dst, src });
+                }
+            }
+            Expr::Assign { name, value, .. } => {
+                let global = self.global(name);
+                self.compile_expr_into(value, global)?;
+                if global != dst {
+                    self.emit(RegInstruction::Move { dst, src: global });
+                }
+            }
+            Expr::Call { callee, args, .. } => {
+                let callee = if args.iter().any(assigns_global) {
+                    self.compile_expr_copy(callee)?
+                } else {
+                    self.compile_expr(callee)?
+                };
+                let (base, args) = self.compile_args(args)?;
+                self.emit(RegInstruction::Call {
+                    dst,
+                    callee,
+                    base,
+                    args,
+                });
+            }
+            Expr::Field { object, name, .. } => {
+                let object = self.compile_expr(object)?;
+                let field = self.fields.index(name);
+                self.emit(RegInstruction::GetField { dst, object, field });
+            }
+            Expr::FieldAssign {
+                object,
+                name,
+                value,
+                ..
+            } => {
+                let src = self.compile_field_assign(object, name, value)?;
+                self.emit(RegInstruction::Move { dst, src });
+            }
+            Expr::Invoke {
+                object, name, args, ..
+            } => {
+                let object = if args.iter().any(assigns_global) {
+                    self.compile_expr_copy(object)?
+                } else {
+                    self.compile_expr(object)?
+                };
+                let sym = self.fields.index(name);
+                let (base, args) = self.compile_args(args)?;
+                self.emit(RegInstruction::Invoke {
+                    dst,
+                    object,
+                    sym,
+                    base,
+                    args,
+                });
+            }
+        }
+
+        self.next_temp = mark;
+        Ok(())
+    }
+
+    /// Returns the register holding the assigned value.
+    fn compile_field_assign(
+        &mut self,
+        object: &Expr,
+        name: &str,
+        value: &Expr,
+    ) -> Result<Reg, Error> {
+        let object = if assigns_global(value) {
+            self.compile_expr_copy(object)?
+        } else {
+            self.compile_expr(object)?
+        };
+        let src = self.compile_expr(value)?;
+        let field = self.fields.index(name);
+        self.emit(RegInstruction::SetField { object, field, src });
+        Ok(src)
+    }
+
+    /// Put the arguments in consecutive temporaries, returning the first one and how many
+    /// there are.
+    fn compile_args(&mut self, args: &[Expr]) -> Result<(Reg, u8), Error> {
+        let base = self.next_temp;
+        for arg in args {
+            let dst = self.temp();
+            self.compile_expr_into(arg, dst)?;
+        }
+
+        debug_assert!(args.len() <= u8::MAX as usize, "bug: too many arguments");
+        Ok((base, args.len() as u8))
+    }
+}
+
+/// A [`RegModule`] linked into a runtime. Only valid for the runtime that loaded it.
+#[derive(Debug)]
+pub struct RegProgram {
+    pub code: Vec<RegInstruction>,
+    pub constants: Vec<Value>,
+    /// The runtime's id for each global register.
+    pub globals: Vec<u32>,
+    pub registers: usize,
+}
+
+/// Link a module into a runtime, the same way [`Runtime::load`] does for stack bytecode.
+pub fn load(runtime: &mut Runtime, module: &RegModule) -> RegProgram {
+    let globals = module
+        .globals
+        .iter()
+        .map(|name| runtime.get_global_index(name) as u32)
+        .collect();
+
+    let fields: Vec<u32> = module
+        .fields
+        .iter()
+        .map(|name| runtime.get_field_index(name))
+        .collect();
+
+    let code = module
+        .code
+        .iter()
+        .map(|inst| match *inst {
+            RegInstruction::GetField { dst, object, field } => RegInstruction::GetField {
+                dst,
+                object,
+                field: fields[field as usize],
+            },
+            RegInstruction::SetField { object, field, src } => RegInstruction::SetField {
+                object,
+                field: fields[field as usize],
+                src,
+            },
+            RegInstruction::Invoke {
+                dst,
+                object,
+                sym,
+                base,
+                args,
+            } => RegInstruction::Invoke {
+                dst,
+                object,
+                sym: fields[sym as usize],
+                base,
+                args,
+            },
+            inst => inst,
+        })
+        .collect();
+
+    let constants = module
+        .constants
+        .iter()
+        .map(|constant| match constant {
+            Constant::Number(num) => Value::Number(*num),
+            Constant::String(string) => Value::String(runtime.interner.intern(string.clone())),
+        })
+        .collect();
+
+    RegProgram {
+        code,
+        constants,
+        globals,
+        registers: module.registers,
+    }
+}
+
+pub struct RegVm<'a> {
+    pub runtime: &'a mut Runtime,
+    pub program: &'a RegProgram,
+    frame: Stack,
+    pub ip: usize,
+    /// Number of instructions executed so far.
+    pub steps: u64,
+}
+
+impl<'a> RegVm<'a> {
+    pub fn new(runtime: &'a mut Runtime, program: &'a RegProgram) -> Self {
+        let mut frame = Stack::default();
+        frame.resize(program.registers, Value::Nil);
+
+        Self {
+            runtime,
+            program,
+            frame,
+            ip: 0,
+            steps: 0,
+        }
+    }
+
+    /// Run until the program halts, fails or requests a GC cycle. Never returns
+    /// [`ControlFlow::Continue`]. On an error the instruction pointer is left on the
+    /// instruction that failed. After a [`ControlFlow::RequestGC`], call
+    /// [`collect_garbage`](Self::collect_garbage) and then `run` again.
+    pub fn run(&mut self) -> Result<ControlFlow, RuntimeError> {
+        for (reg, id) in self.program.globals.iter().enumerate() {
+            self.frame.set(reg, self.runtime.globals[*id as usize]);
+        }
+
+        let flow = self.execute().inspect_err(|_| self.ip -= 1);
+
+        // Hand the globals back so the runtime (and the GC) can see them.
+        for (reg, id) in self.program.globals.iter().enumerate() {
+            self.runtime.globals[*id as usize] = self.frame.get(reg);
+        }
+
+        flow
+    }
+
+    /// Run a GC cycle with the runtime's [`GcPolicy`](crate::gc::GcPolicy). The registers
+    /// are pushed onto the runtime's stack for the cycle, so everything in the frame is a root.
+    pub fn collect_garbage(&mut self) -> CycleScore {
+        let base = self.runtime.stack.len();
+        for value in self.frame.iter() {
+            self.runtime.stack.push(value);
+        }
+
+        let score = self.runtime.collect_garbage();
+        self.runtime.stack.truncate(base);
+        score
+    }
+
+    fn execute(&mut self) -> Result<ControlFlow, RuntimeError> {
+        let program = self.program;
+
+        loop {
+            let inst = program.code[self.ip];
+            self.ip += 1;
+            self.steps += 1;
+
+            match inst {
+                RegInstruction::LoadNil { dst } => self.frame.set(dst as usize, Value::Nil),
+                RegInstruction::LoadBool { dst, value } => {
+                    self.frame.set(dst as usize, Value::Bool(value))
+                }
+                RegInstruction::LoadConst { dst, index } => self
+                    .frame
+                    .set(dst as usize, program.constants[index as usize]),
+                RegInstruction::Move { dst, src } => {
+                    self.frame.set(dst as usize, self.frame.get(src as usize))
+                }
+                RegInstruction::Alloc { dst } => match self.runtime.heap.alloc() {
+                    Some(addr) => self.frame.set(dst as usize, Value::Object(addr)),
+                    None if self.runtime.heap.is_exhausted() => {
+                        return Err(RuntimeError::OutOfMemory);
+                    }
+                    None => {
+                        // Repeat this instruction after the GC cycle.
+                        self.ip -= 1;
+                        return Ok(ControlFlow::RequestGC);
+                    }
+                },
+                RegInstruction::GetField { dst, object, field } => {
+                    let value = self.frame.get(object as usize);
+                    let Value::Object(addr) = value else {
+                        return Err(RuntimeError::NotAnObject(value));
+                    };
+                    let Some(obj) = self.runtime.heap.get(addr) else {
+                        return Err(self.runtime.heap.segfault(addr));
+                    };
+                    self.frame.set(dst as usize, obj.get(field));
+                }
+                RegInstruction::SetField { object, field, src } => {
+                    let value = self.frame.get(object as usize);
+                    let Value::Object(addr) = value else {
+                        return Err(RuntimeError::NotAnObject(value));
+                    };
+                    let Some(obj) = self.runtime.heap.get_mut(addr) else {
+                        return Err(self.runtime.heap.segfault(addr));
+                    };
+                    obj.set(field, self.frame.get(src as usize));
+                }
+                RegInstruction::Call {
+                    dst,
+                    callee,
+                    base,
+                    args,
+                } => {
+                    let callee = self.frame.get(callee as usize);
+                    let Value::FunctionPtr(ptr) = callee else {
+                        return Err(RuntimeError::NotAFunction(callee));
+                    };
+
+                    let runtime = &mut *self.runtime;
+                    let def = &runtime.functions[ptr as usize];
+
+                    if def.args != args {
+                        return Err(RuntimeError::WrongArgumentCount {
+                            expected: def.args,
+                            got: args,
+                        });
+                    }
+
+                    // The arguments are the top of the frame, so it doubles as the stack native
+                    // functions pop their arguments off.
+                    self.frame.truncate(base as usize + args as usize);
+
+                    let mut needs_gc = false;
+                    let mut error = None;
+                    let res = (def.func)(FunctionArgs {
+                        stack: &mut self.frame,
+                        heap: &mut runtime.heap,
+                        strings: &mut runtime.interner,
+                        field_to_id_map: &mut runtime.field_to_id_map,
+                        needs_gc: &mut needs_gc,
+                        error: &mut error,
+                    });
+
+                    self.frame.resize(program.registers, Value::Nil);
+
+                    if let Some(error) = error {
+                        return Err(error);
+                    }
+
+                    if needs_gc {
+                        if self.runtime.heap.is_exhausted() {
+                            return Err(RuntimeError::OutOfMemory);
+                        }
+
+                        // The function left its arguments alone, so the call can be retried
+                        // after the GC cycle.
+                        self.ip -= 1;
+                        return Ok(ControlFlow::RequestGC);
+                    }
+
+                    self.frame.set(dst as usize, res);
+                }
+                RegInstruction::Invoke { .. } => {
+                    // TODO: dispatch methods.
+                    return Err(RuntimeError::MethodCall);
+                }
+                RegInstruction::Jmp { addr } => {
+                    self.ip = (self.ip as isize + addr as isize) as usize;
+                }
+                RegInstruction::JmpIfFalse { cond, addr } => {
+                    if let Value::Bool(false) | Value::Nil = self.frame.get(cond as usize) {
+                        self.ip = (self.ip as isize + addr as isize) as usize;
+                    }
+                }
+                RegInstruction::Halt => {
+                    // Stay on the `Halt` so running again doesn't go past the end.
+                    self.ip -= 1;
+                    return Ok(ControlFlow::Halt);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compiler,
+        lexer::LexerConfig,
+        parser,
+        vm::{HeapConfig, StopReason},
+    };
+
+    fn runtime(size: usize) -> Runtime {
+        let mut runtime = Runtime::new(HeapConfig {
+            size,
+            ..Default::default()
+        });
+        crate::register_builtins(&mut runtime);
+        runtime
+    }
+
+    /// Run `src` on the register VM, collecting garbage whenever it asks.
+    fn run(runtime: &mut Runtime, src: &str) -> Result<RegModule, RuntimeError> {
+        let program = parser::parse(src, &LexerConfig::default()).unwrap();
+        let module = compile(&program).unwrap();
+        let program = load(runtime, &module);
+        let mut vm = RegVm::new(runtime, &program);
+
+        loop {
+            match vm.run()? {
+                ControlFlow::Halt => return Ok(module),
+                ControlFlow::RequestGC => {
+                    vm.collect_garbage();
+                }
+                ControlFlow::Continue => unreachable!("bug: RegVm::run returned Continue"),
+            }
+        }
+    }
+
+    #[test]
+    fn bench_scripts_match_the_stack_vm() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/bench");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let src = std::fs::read_to_string(&path).unwrap();
+
+            let mut registers = runtime(20);
+            let module = run(&mut registers, &src).unwrap();
+            let mut expected = registers.describe_globals(&module.globals);
+
+            let mut stack = runtime(20);
+            let program = parser::parse(&src, &LexerConfig::default()).unwrap();
+            let module = compiler::compile(&program).unwrap();
+            let program = stack.load(&module);
+            let mut vm = stack.spawn_vm(&program);
+            assert!(matches!(vm.run(u64::MAX), StopReason::Halt));
+            let mut actual = stack.describe_globals(&module.globals);
+
+            // The two compilers number the globals differently.
+            expected.sort();
+            actual.sort();
+            assert_eq!(actual, expected, "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn temporaries_survive_gc_cycles() {
+        let mut runtime = runtime(3);
+        let next = runtime.get_field_index("next");
+        runtime.register_function("link", 2, move |args| {
+            let to = args.stack.pop().unwrap();
+            let from = args.stack.pop().unwrap();
+            let Value::Object(handle) = from else {
+                panic!("expected an object, got {from:?}");
+            };
+            match args.heap.get_mut(handle) {
+                Some(object) => object.set(next, to),
+                None => return args.segfault(handle),
+            }
+            from
+        });
+
+        // The first ALLOC only lives in a temporary when the second one needs a GC cycle.
+        run(
+            &mut runtime,
+            "a = ALLOC; b = ALLOC; a = nil; x = link(ALLOC, ALLOC);",
+        )
+        .unwrap();
+
+        let Some(Value::Object(x)) = runtime.get_global("x") else {
+            panic!("x isn't an object");
+        };
+        let Value::Object(y) = runtime.heap.get(x).unwrap().get(next) else {
+            panic!("x.next isn't an object");
+        };
+        assert!(runtime.heap.get(y).is_some());
+        assert_eq!(runtime.gc_metrics.total_cycles, 1);
+    }
+
+    #[test]
+    fn a_full_heap_is_out_of_memory() {
+        let mut runtime = runtime(2);
+        let result = run(&mut runtime, "a = ALLOC; b = ALLOC; c = ALLOC;");
+        assert!(matches!(result, Err(RuntimeError::OutOfMemory)));
+    }
+}